@@ -0,0 +1,528 @@
+use std::{collections::BTreeMap, fmt, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tracing::info;
+
+use crate::{is_valid_username, room_name, Message, Peer, State, LOBBY, USERNAME_RULES};
+
+// what a command may touch: the shared state and the client that ran it
+pub struct Context<'a> {
+    pub state: &'a State,
+    pub addr: SocketAddr,
+    pub peer: &'a mut Peer,
+}
+
+// whether the client stays connected after a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+// sent back to the client as a notice
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("Unknown command /{0}, /help lists the commands")]
+    Unknown(String),
+
+    #[error("Usage: {0}")]
+    Usage(String),
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// a slash command, e.g. `/nick <name>`
+#[async_trait]
+pub trait Command: Send + Sync {
+    // name without the slash
+    fn name(&self) -> &'static str;
+
+    // arguments as shown by /help, e.g. `<name>`
+    fn args(&self) -> &'static str {
+        ""
+    }
+
+    fn about(&self) -> &'static str;
+
+    // arg is the rest of the line, trimmed
+    async fn run(&self, ctx: &mut Context<'_>, arg: &str) -> Result<Flow, CommandError>;
+}
+
+// commands by name, register new ones with `with`
+pub struct Commands {
+    by_name: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self {
+            by_name: BTreeMap::new(),
+        }
+    }
+
+    // a command of the same name is replaced
+    pub fn with(mut self, command: impl Command + 'static) -> Self {
+        self.by_name.insert(command.name(), Box::new(command));
+        self
+    }
+
+    // line is what followed the slash, e.g. `nick alice`
+    pub async fn run(&self, ctx: &mut Context<'_>, line: &str) -> Result<Flow, CommandError> {
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match self.by_name.get(name.to_ascii_lowercase().as_str()) {
+            Some(command) => command.run(ctx, arg.trim()).await,
+            None => Err(CommandError::Unknown(name.to_string())),
+        }
+    }
+
+    fn usage(command: &dyn Command) -> String {
+        match command.args() {
+            "" => format!("/{}", command.name()),
+            args => format!("/{} {}", command.name(), args),
+        }
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self::new()
+            .with(Join)
+            .with(Leave)
+            .with(Rooms)
+            .with(Nick)
+            .with(Who)
+            .with(Me)
+            .with(Msg)
+            .with(Quit)
+            .with(Help)
+    }
+}
+
+impl fmt::Debug for Commands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.by_name.keys()).finish()
+    }
+}
+
+impl Context<'_> {
+    async fn reply(&self, content: impl Into<String>) {
+        let message = Arc::new(Message::notice(content));
+        self.state.send(self.addr, message).await;
+    }
+}
+
+fn usage(command: &dyn Command) -> CommandError {
+    CommandError::Usage(Commands::usage(command))
+}
+
+struct Join;
+struct Leave;
+struct Rooms;
+struct Nick;
+struct Who;
+struct Me;
+struct Msg;
+struct Quit;
+struct Help;
+
+#[async_trait]
+impl Command for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn args(&self) -> &'static str {
+        "#room"
+    }
+
+    fn about(&self) -> &'static str {
+        "switch to a room, it is created if nobody is in it"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, arg: &str) -> Result<Flow, CommandError> {
+        if arg.is_empty() {
+            return Err(usage(self));
+        }
+        let room = room_name(arg).ok_or_else(|| {
+            CommandError::Invalid("Room names are 1-32 letters, digits, - or _".to_string())
+        })?;
+        ctx.peer.switch_room(ctx.state, ctx.addr, room).await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn about(&self) -> &'static str {
+        "go back to #lobby"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, _arg: &str) -> Result<Flow, CommandError> {
+        ctx.peer
+            .switch_room(ctx.state, ctx.addr, LOBBY.to_string())
+            .await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Rooms {
+    fn name(&self) -> &'static str {
+        "rooms"
+    }
+
+    fn about(&self) -> &'static str {
+        "list the rooms and how many are in them"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, _arg: &str) -> Result<Flow, CommandError> {
+        ctx.reply(format!("Rooms: {}", ctx.state.room_list())).await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn args(&self) -> &'static str {
+        "<name>"
+    }
+
+    fn about(&self) -> &'static str {
+        "change your username"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, arg: &str) -> Result<Flow, CommandError> {
+        if arg.is_empty() {
+            return Err(usage(self));
+        }
+        if !is_valid_username(arg) {
+            return Err(CommandError::Invalid(USERNAME_RULES.to_string()));
+        }
+        if !ctx.state.rename(ctx.addr, &ctx.peer.username, arg) {
+            return Err(CommandError::Invalid(format!("{} is taken", arg)));
+        }
+        let old = std::mem::replace(&mut ctx.peer.username, arg.to_string());
+        let message = Message::notice(format!("{} is now known as {}", old, arg));
+        info!("{}", message);
+        ctx.state
+            .broadcast(&ctx.peer.room, ctx.addr, Arc::new(message))
+            .await;
+        ctx.reply(format!("You are now known as {}", arg)).await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn about(&self) -> &'static str {
+        "list who is in your room"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, _arg: &str) -> Result<Flow, CommandError> {
+        let members = ctx.state.members(&ctx.peer.room);
+        ctx.reply(format!("In {}: {}", ctx.peer.room, members.join(", ")))
+            .await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn args(&self) -> &'static str {
+        "<action>"
+    }
+
+    fn about(&self) -> &'static str {
+        "tell the room what you are doing, e.g. /me waves"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, arg: &str) -> Result<Flow, CommandError> {
+        if arg.is_empty() {
+            return Err(usage(self));
+        }
+        let message = Arc::new(Message::action(&ctx.peer.username, arg));
+        ctx.state.broadcast(&ctx.peer.room, ctx.addr, message).await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Msg {
+    fn name(&self) -> &'static str {
+        "msg"
+    }
+
+    fn args(&self) -> &'static str {
+        "<user> <text>"
+    }
+
+    fn about(&self) -> &'static str {
+        "send a message only user sees, in any room"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, arg: &str) -> Result<Flow, CommandError> {
+        let Some((recipient, content)) = arg.split_once(' ') else {
+            return Err(usage(self));
+        };
+        let content = content.trim();
+        if content.is_empty() {
+            return Err(usage(self));
+        }
+        let addr = ctx
+            .state
+            .find(recipient)
+            .ok_or_else(|| CommandError::Invalid(format!("Nobody is named {}", recipient)))?;
+        if addr == ctx.addr {
+            return Err(CommandError::Invalid(
+                "That is you, messages to yourself go nowhere".to_string(),
+            ));
+        }
+        // the name as its owner spells it
+        let recipient = ctx
+            .state
+            .peer
+            .get(&addr)
+            .map(|client| client.username.clone())
+            .unwrap_or_else(|| recipient.to_string());
+        let message = Arc::new(Message::direct(&ctx.peer.username, &recipient, content));
+        ctx.state.send(addr, message.clone()).await;
+        ctx.state.send(ctx.addr, message).await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[async_trait]
+impl Command for Quit {
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+
+    fn about(&self) -> &'static str {
+        "disconnect"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, _arg: &str) -> Result<Flow, CommandError> {
+        ctx.reply("Bye").await;
+        Ok(Flow::Quit)
+    }
+}
+
+#[async_trait]
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn about(&self) -> &'static str {
+        "list the commands"
+    }
+
+    async fn run(&self, ctx: &mut Context<'_>, _arg: &str) -> Result<Flow, CommandError> {
+        let commands = &ctx.state.commands.by_name;
+        let width = commands
+            .values()
+            .map(|command| Commands::usage(command.as_ref()).len())
+            .max()
+            .unwrap_or_default();
+        for command in commands.values() {
+            let usage = Commands::usage(command.as_ref());
+            ctx.reply(format!("{:width$}  {}", usage, command.about()))
+                .await;
+        }
+        ctx.reply("Start a line with // to send it with a single slash")
+            .await;
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{connect, peer, received};
+
+    async fn run(
+        state: &State,
+        addr: SocketAddr,
+        peer: &mut Peer,
+        line: &str,
+    ) -> Result<Flow, CommandError> {
+        let mut ctx = Context { state, addr, peer };
+        state.commands.run(&mut ctx, line).await
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_errors() {
+        let state = State::default();
+        let (addr, mut rx) = connect(&state, 1001, "alice");
+        let mut alice = peer(&state, addr, "alice").await;
+        let err = run(&state, addr, &mut alice, "dance now")
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, CommandError::Unknown(name) if name == "dance"));
+        assert_eq!(
+            err.to_string(),
+            "Unknown command /dance, /help lists the commands"
+        );
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn command_names_ignore_case() {
+        let state = State::default();
+        let (addr, _rx) = connect(&state, 1001, "alice");
+        let mut alice = peer(&state, addr, "alice").await;
+        let flow = run(&state, addr, &mut alice, "JOIN #rust").await.unwrap();
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(alice.room, "#rust");
+    }
+
+    #[tokio::test]
+    async fn missing_arguments_show_the_usage() {
+        let state = State::default();
+        let (addr, _rx) = connect(&state, 1001, "alice");
+        let mut alice = peer(&state, addr, "alice").await;
+        for (line, usage) in [
+            ("join", "Usage: /join #room"),
+            ("join   ", "Usage: /join #room"),
+            ("nick", "Usage: /nick <name>"),
+            ("me", "Usage: /me <action>"),
+        ] {
+            let err = run(&state, addr, &mut alice, line).await.unwrap_err();
+            assert!(matches!(err, CommandError::Usage(_)), "{}", line);
+            assert_eq!(err.to_string(), usage);
+        }
+        let err = run(&state, addr, &mut alice, "join #no way")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CommandError::Invalid(_)));
+        assert_eq!(alice.room, LOBBY);
+    }
+
+    #[tokio::test]
+    async fn help_lists_every_command() {
+        let state = State::default();
+        let (addr, mut rx) = connect(&state, 1001, "alice");
+        let mut alice = peer(&state, addr, "alice").await;
+        let flow = run(&state, addr, &mut alice, "help").await.unwrap();
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(
+            received(&mut rx),
+            [
+                "-- /help               list the commands",
+                "-- /join #room         switch to a room, it is created if nobody is in it",
+                "-- /leave              go back to #lobby",
+                "-- /me <action>        tell the room what you are doing, e.g. /me waves",
+                "-- /msg <user> <text>  send a message only user sees, in any room",
+                "-- /nick <name>        change your username",
+                "-- /quit               disconnect",
+                "-- /rooms              list the rooms and how many are in them",
+                "-- /who                list who is in your room",
+                "-- Start a line with // to send it with a single slash",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn quit_ends_the_connection() {
+        let state = State::default();
+        let (addr, mut rx) = connect(&state, 1001, "alice");
+        let mut alice = peer(&state, addr, "alice").await;
+        assert_eq!(
+            run(&state, addr, &mut alice, "quit").await.unwrap(),
+            Flow::Quit
+        );
+        assert_eq!(received(&mut rx), ["-- Bye"]);
+    }
+
+    #[tokio::test]
+    async fn nicks_taken_by_others_are_rejected() {
+        let state = State::default();
+        let (alice_addr, _alice_rx) = connect(&state, 1001, "alice");
+        let (bob_addr, mut bob_rx) = connect(&state, 1002, "bob");
+        peer(&state, alice_addr, "alice").await;
+        let mut bob = peer(&state, bob_addr, "bob").await;
+        for name in ["alice", "ALICE"] {
+            let err = run(&state, bob_addr, &mut bob, &format!("nick {}", name))
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), format!("{} is taken", name));
+        }
+        let err = run(&state, bob_addr, &mut bob, "nick #bob")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), USERNAME_RULES);
+        assert_eq!(bob.username, "bob");
+        assert_eq!(state.find("bob"), Some(bob_addr));
+        assert!(received(&mut bob_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn renaming_frees_the_old_nick() {
+        let state = State::default();
+        let (alice_addr, mut alice_rx) = connect(&state, 1001, "alice");
+        let (bob_addr, mut bob_rx) = connect(&state, 1002, "bob");
+        let mut alice = peer(&state, alice_addr, "alice").await;
+        let mut bob = peer(&state, bob_addr, "bob").await;
+        received(&mut alice_rx);
+
+        run(&state, alice_addr, &mut alice, "nick ally")
+            .await
+            .unwrap();
+        assert_eq!(alice.username, "ally");
+        assert_eq!(received(&mut alice_rx), ["-- You are now known as ally"]);
+        assert_eq!(received(&mut bob_rx), ["-- alice is now known as ally"]);
+        assert_eq!(state.find("alice"), None);
+
+        run(&state, bob_addr, &mut bob, "nick Alice").await.unwrap();
+        assert_eq!(state.find("alice"), Some(bob_addr));
+        assert_eq!(state.find("ally"), Some(alice_addr));
+        assert_eq!(state.members(LOBBY), ["Alice", "ally"]);
+    }
+
+    #[tokio::test]
+    async fn direct_messages_need_a_known_user() {
+        let state = State::default();
+        let (alice_addr, mut alice_rx) = connect(&state, 1001, "alice");
+        let (bob_addr, mut bob_rx) = connect(&state, 1002, "bob");
+        let mut alice = peer(&state, alice_addr, "alice").await;
+        let mut bob = peer(&state, bob_addr, "bob").await;
+        run(&state, bob_addr, &mut bob, "join #rust").await.unwrap();
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+
+        let err = run(&state, alice_addr, &mut alice, "msg carol hi")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Nobody is named carol");
+        let err = run(&state, alice_addr, &mut alice, "msg bob")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Usage: /msg <user> <text>");
+        assert!(received(&mut bob_rx).is_empty());
+
+        // rooms don't matter, and names are matched in any case
+        run(&state, alice_addr, &mut alice, "msg BOB hi there")
+            .await
+            .unwrap();
+        assert_eq!(received(&mut bob_rx), ["alice -> bob: hi there"]);
+        assert_eq!(received(&mut alice_rx), ["alice -> bob: hi there"]);
+    }
+}
@@ -0,0 +1,546 @@
+mod commands;
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Debug},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use commands::{Commands, Context, Flow};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{stream::SplitStream, SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+#[allow(unused_imports)]
+use tracing::{info, level_filters::LevelFilter, warn};
+#[allow(unused_imports)]
+use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+
+const MAX_MESSAGES: usize = 128;
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_USERNAME_LEN: usize = 32;
+const USERNAME_RULES: &str =
+    "Usernames are 1-32 characters without spaces, not starting with # or /";
+// everyone starts here, and goes back here on /leave
+const LOBBY: &str = "#lobby";
+
+#[derive(Debug, Default)]
+struct State {
+    peer: DashMap<SocketAddr, Client>,
+    // lowercased username -> address, no two clients share a name
+    users: DashMap<String, SocketAddr>,
+    // room name -> addresses of its members, rooms go away with their last member
+    rooms: DashMap<String, HashSet<SocketAddr>>,
+    // slash commands clients can run, the built-in ones unless more are registered
+    commands: Commands,
+}
+
+// what other tasks need of a connected client
+#[derive(Debug)]
+struct Client {
+    username: String,
+    sender: mpsc::Sender<Arc<Message>>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    username: String,
+    room: String,
+    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
+}
+
+#[derive(Debug)]
+enum Message {
+    UserJoined(String),
+    UserLeft(String),
+    Chat {
+        sender: String,
+        content: String,
+    },
+    // "/me waves"
+    Action {
+        sender: String,
+        content: String,
+    },
+    // "/msg bob hi", shown to both of them
+    Direct {
+        sender: String,
+        recipient: String,
+        content: String,
+    },
+    // reply of the server to a single client
+    Notice(String),
+}
+
+impl State {
+    // send to every member of room except addr
+    async fn broadcast(&self, room: &str, addr: SocketAddr, message: Arc<Message>) {
+        // collect first, the map guards must not be held across an await
+        let members: Vec<SocketAddr> = match self.rooms.get(room) {
+            Some(members) => members.iter().filter(|&&a| a != addr).copied().collect(),
+            None => return,
+        };
+        for member in members {
+            self.send(member, message.clone()).await;
+        }
+    }
+
+    async fn send(&self, addr: SocketAddr, message: Arc<Message>) {
+        let Some(sender) = self.peer.get(&addr).map(|client| client.sender.clone()) else {
+            return;
+        };
+        if let Err(e) = sender.send(message).await {
+            warn!("Failed to send message to {}: {}", addr, e);
+            // if send failed, peer might be gone, remote peer from state
+            self.peer.remove(&addr);
+        }
+    }
+
+    async fn join(&self, addr: SocketAddr, username: &str, room: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(addr);
+        let message = Arc::new(Message::user_joined(username, room));
+        info!("{}", message);
+        self.broadcast(room, addr, message).await;
+    }
+
+    async fn leave(&self, addr: SocketAddr, username: &str, room: &str) {
+        if let Some(mut members) = self.rooms.get_mut(room) {
+            members.remove(&addr);
+        }
+        self.rooms.remove_if(room, |_, members| members.is_empty());
+        let message = Arc::new(Message::user_left(username, room));
+        info!("{}", message);
+        self.broadcast(room, addr, message).await;
+    }
+
+    // usernames of the members of room, sorted
+    fn members(&self, room: &str) -> Vec<String> {
+        let addrs: Vec<SocketAddr> = match self.rooms.get(room) {
+            Some(members) => members.iter().copied().collect(),
+            None => return Vec::new(),
+        };
+        let mut names: Vec<String> = addrs
+            .iter()
+            .filter_map(|addr| self.peer.get(addr).map(|client| client.username.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // take username for addr, false if another client has it
+    fn claim(&self, addr: SocketAddr, username: &str) -> bool {
+        match self.users.entry(username.to_lowercase()) {
+            Entry::Occupied(owner) => *owner.get() == addr,
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                true
+            }
+        }
+    }
+
+    fn release(&self, addr: SocketAddr, username: &str) {
+        self.users
+            .remove_if(&username.to_lowercase(), |_, owner| *owner == addr);
+    }
+
+    // address of the client named username, in any case
+    fn find(&self, username: &str) -> Option<SocketAddr> {
+        self.users.get(&username.to_lowercase()).map(|addr| *addr)
+    }
+
+    // false if another client has the new name
+    fn rename(&self, addr: SocketAddr, old: &str, new: &str) -> bool {
+        if !self.claim(addr, new) {
+            return false;
+        }
+        // only changing the case keeps the same key
+        if old.to_lowercase() != new.to_lowercase() {
+            self.release(addr, old);
+        }
+        if let Some(mut client) = self.peer.get_mut(&addr) {
+            client.username = new.to_string();
+        }
+        true
+    }
+
+    // "#lobby (3), #rust (1)", sorted by name
+    fn room_list(&self) -> String {
+        let mut rooms: Vec<(String, usize)> = self
+            .rooms
+            .iter()
+            .map(|room| (room.key().clone(), room.value().len()))
+            .collect();
+        rooms.sort();
+        let rooms: Vec<String> = rooms
+            .into_iter()
+            .map(|(name, members)| format!("{} ({})", name, members))
+            .collect();
+        rooms.join(", ")
+    }
+
+    async fn add(
+        &self,
+        addr: SocketAddr,
+        username: String,
+        stream: Framed<TcpStream, LinesCodec>,
+    ) -> Peer {
+        let (tx, mut rx) = mpsc::channel(MAX_MESSAGES);
+        let client = Client {
+            username: username.clone(),
+            sender: tx,
+        };
+        self.peer.insert(addr, client);
+
+        let (mut stream_sender, stream_receiver) = stream.split();
+
+        // receive message from others, and send them to the client
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Err(e) = stream_sender.send(message.to_string()).await {
+                    warn!("Failed to send message to {}: {}", addr, e);
+                    break;
+                }
+            }
+        });
+
+        //return peer
+        Peer {
+            username,
+            room: LOBBY.to_string(),
+            stream: stream_receiver,
+        }
+    }
+}
+
+impl Peer {
+    async fn switch_room(&mut self, state: &State, addr: SocketAddr, room: String) {
+        if room == self.room {
+            state
+                .send(
+                    addr,
+                    Arc::new(Message::notice(format!("You are already in {}", room))),
+                )
+                .await;
+            return;
+        }
+        state.leave(addr, &self.username, &self.room).await;
+        state.join(addr, &self.username, &room).await;
+        let notice = Message::notice(format!("You are now in {}", room));
+        state.send(addr, Arc::new(notice)).await;
+        self.room = room;
+    }
+}
+
+impl Message {
+    fn user_joined(username: &str, room: &str) -> Self {
+        let content = format!("{} has joined {}", username, room);
+        Self::UserJoined(content.to_string())
+    }
+
+    fn user_left(username: &str, room: &str) -> Self {
+        let content = format!("{} has left {}", username, room);
+        Self::UserLeft(content.to_string())
+    }
+
+    fn action(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Action {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+
+    fn direct(sender: &str, recipient: &str, content: impl Into<String>) -> Self {
+        Self::Direct {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            content: content.into(),
+        }
+    }
+
+    fn notice(content: impl Into<String>) -> Self {
+        Self::Notice(content.into())
+    }
+
+    fn chat(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Chat {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UserJoined(content) => write!(f, "[{}]", content),
+            Self::UserLeft(content) => write!(f, "[{} :(]", content),
+            Self::Chat { sender, content } => write!(f, "{}: {}", sender, content),
+            Self::Action { sender, content } => write!(f, "* {} {}", sender, content),
+            Self::Direct {
+                sender,
+                recipient,
+                content,
+            } => write!(f, "{} -> {}: {}", sender, recipient, content),
+            Self::Notice(content) => write!(f, "-- {}", content),
+        }
+    }
+}
+
+// "#Rust" or "rust" -> "#rust", None unless 1-32 letters, digits, - or _
+fn room_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix('#').unwrap_or(name);
+    let valid = (1..=MAX_ROOM_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| format!("#{}", name.to_ascii_lowercase()))
+}
+
+// 1-32 characters without whitespace, not starting like a room or a command
+fn is_valid_username(name: &str) -> bool {
+    (1..=MAX_USERNAME_LEN).contains(&name.chars().count())
+        && !name.starts_with(['#', '/'])
+        && !name.chars().any(char::is_whitespace)
+}
+
+async fn handle_client(state: Arc<State>, addr: SocketAddr, stream: TcpStream) -> Result<()> {
+    let mut stream = Framed::new(stream, LinesCodec::new());
+    stream.send("Enter your username:").await?;
+
+    // ask again until the name is valid and free
+    let username = loop {
+        let username = match stream.next().await {
+            Some(Ok(username)) => username.trim().to_string(),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(()),
+        };
+        if !is_valid_username(&username) {
+            stream
+                .send(format!("{}, try another one:", USERNAME_RULES))
+                .await?;
+        } else if !state.claim(addr, &username) {
+            stream
+                .send(format!("{} is taken, try another one:", username))
+                .await?;
+        } else {
+            break username;
+        }
+    };
+
+    let mut peer = state.add(addr, username, stream).await;
+
+    state.join(addr, &peer.username, &peer.room).await;
+    let welcome = Message::notice(format!(
+        "You are in {}, /help lists the commands",
+        peer.room
+    ));
+    state.send(addr, Arc::new(welcome)).await;
+
+    while let Some(line) = peer.stream.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to read line from {}: {}", addr, e);
+                break;
+            }
+        };
+
+        // "//" sends a line starting with a slash
+        match line.strip_prefix('/') {
+            Some(command) if !command.starts_with('/') => {
+                let mut ctx = Context {
+                    state: &state,
+                    addr,
+                    peer: &mut peer,
+                };
+                match state.commands.run(&mut ctx, command).await {
+                    Ok(Flow::Continue) => {}
+                    Ok(Flow::Quit) => break,
+                    Err(e) => {
+                        state
+                            .send(addr, Arc::new(Message::notice(e.to_string())))
+                            .await
+                    }
+                }
+            }
+            content => {
+                let message = Message::chat(&peer.username, content.unwrap_or(&line));
+                state.broadcast(&peer.room, addr, Arc::new(message)).await;
+            }
+        }
+    }
+
+    //remote the peer from the state
+    state.peer.remove(&addr);
+    state.release(addr, &peer.username);
+    // notify the room when peer has left the chat or line reading failed
+    state.leave(addr, &peer.username, &peer.room).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
+    // tracing_subscriber::registry().with(layer).init();
+    console_subscriber::init();
+
+    let addr = "0.0.0.0:8080";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", addr);
+
+    let state = Arc::new(State::default());
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Accepted connection from {}", addr);
+        let state_cloned = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(state_cloned, addr, stream).await {
+                warn!("Failed to handle client {}: {}", addr, e);
+            };
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_are_normalized() {
+        assert_eq!(room_name("#Rust").as_deref(), Some("#rust"));
+        assert_eq!(room_name("rust").as_deref(), Some("#rust"));
+        assert_eq!(room_name("dev_ops-2").as_deref(), Some("#dev_ops-2"));
+        let longest = "a".repeat(MAX_ROOM_NAME_LEN);
+        assert_eq!(room_name(&longest), Some(format!("#{}", longest)));
+    }
+
+    #[test]
+    fn invalid_room_names_are_rejected() {
+        let too_long = "a".repeat(MAX_ROOM_NAME_LEN + 1);
+        for name in ["", "#", "##rust", "rust talk", "rüst", "a.b", &too_long] {
+            assert_eq!(room_name(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn usernames_are_checked() {
+        let longest = "ä".repeat(MAX_USERNAME_LEN);
+        for name in ["alice", "Bob_2", "a", "ünï", "a#b", "a/b", &longest] {
+            assert!(is_valid_username(name), "{}", name);
+        }
+        let too_long = "a".repeat(MAX_USERNAME_LEN + 1);
+        for name in ["", "#alice", "/alice", "al ice", "alice\t", &too_long] {
+            assert!(!is_valid_username(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn usernames_are_unique_in_any_case() {
+        let state = State::default();
+        let alice = SocketAddr::from(([127, 0, 0, 1], 1001));
+        let bob = SocketAddr::from(([127, 0, 0, 1], 1002));
+        assert!(state.claim(alice, "Alice"));
+        // claiming your own name again is fine
+        assert!(state.claim(alice, "alice"));
+        assert!(!state.claim(bob, "ALICE"));
+        // only the owner can release a name
+        state.release(bob, "alice");
+        assert_eq!(state.find("alice"), Some(alice));
+        state.release(alice, "Alice");
+        assert!(state.claim(bob, "alice"));
+    }
+
+    // a client of state at 127.0.0.1:port, its messages are kept in the receiver
+    pub(crate) fn connect(
+        state: &State,
+        port: u16,
+        username: &str,
+    ) -> (SocketAddr, mpsc::Receiver<Arc<Message>>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (tx, rx) = mpsc::channel(MAX_MESSAGES);
+        let client = Client {
+            username: username.to_string(),
+            sender: tx,
+        };
+        state.peer.insert(addr, client);
+        assert!(state.claim(addr, username));
+        (addr, rx)
+    }
+
+    // the messages a client got so far, as they are sent over the wire
+    pub(crate) fn received(rx: &mut mpsc::Receiver<Arc<Message>>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| message.to_string())
+            .collect()
+    }
+
+    // a peer that joined the lobby, reading from a loopback connection nobody writes to
+    pub(crate) async fn peer(state: &State, addr: SocketAddr, username: &str) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_, stream) = Framed::new(stream, LinesCodec::new()).split::<String>();
+        state.join(addr, username, LOBBY).await;
+        Peer {
+            username: username.to_string(),
+            room: LOBBY.to_string(),
+            stream,
+        }
+    }
+
+    #[tokio::test]
+    async fn switching_rooms_moves_the_peer() {
+        let state = State::default();
+        let (alice_addr, mut alice_rx) = connect(&state, 1001, "alice");
+        let (bob_addr, mut bob_rx) = connect(&state, 1002, "bob");
+        let mut alice = peer(&state, alice_addr, "alice").await;
+        let mut bob = peer(&state, bob_addr, "bob").await;
+        assert_eq!(received(&mut alice_rx), ["[bob has joined #lobby]"]);
+
+        alice
+            .switch_room(&state, alice_addr, "#rust".to_string())
+            .await;
+        assert_eq!(alice.room, "#rust");
+        assert_eq!(received(&mut alice_rx), ["-- You are now in #rust"]);
+        assert_eq!(received(&mut bob_rx), ["[alice has left #lobby :(]"]);
+        assert_eq!(state.room_list(), "#lobby (1), #rust (1)");
+
+        bob.switch_room(&state, bob_addr, "#rust".to_string()).await;
+        assert_eq!(received(&mut alice_rx), ["[bob has joined #rust]"]);
+        assert_eq!(received(&mut bob_rx), ["-- You are now in #rust"]);
+        // the lobby went away with its last member
+        assert_eq!(state.room_list(), "#rust (2)");
+
+        // chat stays in the room it was sent to
+        bob.switch_room(&state, bob_addr, LOBBY.to_string()).await;
+        let message = Arc::new(Message::chat("alice", "hi"));
+        state.broadcast(&alice.room, alice_addr, message).await;
+        assert_eq!(received(&mut bob_rx), ["-- You are now in #lobby"]);
+        assert_eq!(received(&mut alice_rx), ["[bob has left #rust :(]"]);
+    }
+
+    #[tokio::test]
+    async fn switching_to_the_current_room_only_tells_the_peer() {
+        let state = State::default();
+        let (alice_addr, mut alice_rx) = connect(&state, 1001, "alice");
+        let (bob_addr, mut bob_rx) = connect(&state, 1002, "bob");
+        let mut alice = peer(&state, alice_addr, "alice").await;
+        peer(&state, bob_addr, "bob").await;
+        received(&mut alice_rx);
+
+        alice
+            .switch_room(&state, alice_addr, LOBBY.to_string())
+            .await;
+        assert_eq!(alice.room, LOBBY);
+        assert_eq!(received(&mut alice_rx), ["-- You are already in #lobby"]);
+        assert!(received(&mut bob_rx).is_empty());
+        assert_eq!(state.room_list(), "#lobby (2)");
+    }
+}
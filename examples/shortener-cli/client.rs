@@ -0,0 +1,130 @@
+use anyhow::{anyhow, bail, Context, Result};
+use http::{
+    header::{AUTHORIZATION, HOST, LOCATION},
+    Method, StatusCode,
+};
+use reqwest::{redirect::Policy, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+/// Http client of one shortener server.
+#[derive(Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    server: Url,
+    token: Option<String>,
+    domain: Option<String>,
+}
+
+/// Where a short link goes, as answered to a HEAD request.
+#[derive(Debug, Serialize)]
+pub struct Resolved {
+    pub id: String,
+    pub status: u16,
+    /// `None` when the link asks for a password first
+    pub location: Option<String>,
+}
+
+/// Error body of the shortener, `{"error": "not_found", "message": "..."}`.
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: String,
+    message: String,
+}
+
+impl Client {
+    /// `domain` is sent as the `Host`, to work on the links of a vanity domain.
+    pub fn new(server: Url, token: Option<String>, domain: Option<String>) -> Result<Self> {
+        // resolve shows the redirect itself, not where it ends up
+        let http = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()?;
+        Ok(Self {
+            http,
+            server,
+            token,
+            domain,
+        })
+    }
+
+    /// `POST /` with a `ShortenReq` body.
+    pub async fn shorten(&self, req: &impl Serialize) -> Result<Value> {
+        send(self.request(Method::POST, &[])?.json(req)).await
+    }
+
+    /// `HEAD /:id`, the server doesn't count a click for it. A whole short url is
+    /// requested as it is, so the signature of signed links comes along.
+    pub async fn resolve(&self, link: &str) -> Result<Resolved> {
+        let (id, req) = match Url::parse(link) {
+            Ok(url) => (crate::id_of(link), self.http.head(url)),
+            Err(_) => (link, self.request(Method::HEAD, &[link])?),
+        };
+        let res = req.send().await?;
+        let status = res.status();
+        if !status.is_redirection() && status != StatusCode::OK {
+            // head responses have no body to take the message from
+            bail!("{id}: {status}");
+        }
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .map(|v| v.to_str().map(String::from))
+            .transpose()
+            .context("location is not valid utf-8")?;
+        Ok(Resolved {
+            id: id.to_string(),
+            status: status.as_u16(),
+            location,
+        })
+    }
+
+    /// `GET /admin/urls`, one page of links.
+    pub async fn list(&self, query: &impl Serialize) -> Result<Value> {
+        send(self.request(Method::GET, &["admin", "urls"])?.query(query)).await
+    }
+
+    /// `DELETE /admin/urls/:id`, the link can be restored by the admin api.
+    pub async fn delete(&self, id: &str) -> Result<Value> {
+        send(self.request(Method::DELETE, &["admin", "urls", id])?).await
+    }
+
+    /// `GET /:id/stats`.
+    pub async fn stats(&self, id: &str, query: &impl Serialize) -> Result<Value> {
+        send(self.request(Method::GET, &[id, "stats"])?.query(query)).await
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.server.clone();
+        // segments are percent encoded, so ids can't escape the path
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("server must be an http(s) url: {}", self.server))?
+            .pop_if_empty()
+            .extend(segments);
+        let mut req = self.http.request(method, url);
+        if let Some(token) = &self.token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if let Some(domain) = &self.domain {
+            req = req.header(HOST, domain);
+        }
+        Ok(req)
+    }
+}
+
+/// The json body of a successful response, or the server's error message.
+async fn send(req: RequestBuilder) -> Result<Value> {
+    let res = req.send().await?;
+    if res.status().is_success() {
+        return Ok(res.json().await?);
+    }
+    Err(error(res).await)
+}
+
+async fn error(res: Response) -> anyhow::Error {
+    let status = res.status();
+    match res.json::<ErrorBody>().await {
+        Ok(body) => anyhow!("{} ({status}, {})", body.message, body.error),
+        Err(_) => anyhow!("request failed with {status}"),
+    }
+}
@@ -0,0 +1,297 @@
+mod client;
+mod output;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use client::Client;
+use output::{Format, Table};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+/// Client of the shortener service, e.g.
+/// `cargo run --example shortener-cli -- shorten https://example.com`.
+#[derive(Debug, Parser)]
+#[command(name = "shortener-cli")]
+struct Cli {
+    /// base url of the shortener
+    #[arg(
+        long,
+        global = true,
+        env = "SHORTENER_SERVER",
+        default_value = "http://127.0.0.1:9876/"
+    )]
+    server: Url,
+    /// admin token or api key, list and delete need one
+    #[arg(long, global = true, env = "SHORTENER_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// vanity domain whose links to work on, sent as the host header
+    #[arg(long, global = true, env = "SHORTENER_DOMAIN")]
+    domain: Option<String>,
+    #[arg(long, short, global = true, value_enum, default_value_t = Format::Table)]
+    output: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shorten a url
+    Shorten(ShortenArgs),
+    /// Show where a link goes without counting a click
+    Resolve {
+        /// id or short url, signed links need their whole short url
+        id: String,
+    },
+    /// List links, newest first
+    List(ListArgs),
+    /// Soft delete a link
+    Delete {
+        /// id or short url
+        id: String,
+    },
+    /// Click counts of a link per day and hour
+    Stats {
+        /// id or short url
+        id: String,
+        /// number of daily buckets, 30 by default
+        #[arg(long)]
+        days: Option<u32>,
+        /// number of hourly buckets, 24 by default
+        #[arg(long)]
+        hours: Option<u32>,
+    },
+}
+
+/// Body of `POST /`.
+#[derive(Debug, Args, Serialize)]
+struct ShortenArgs {
+    url: String,
+    /// vanity id instead of a generated one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    /// rfc3339, e.g. 2030-01-01T00:00:00Z
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_clicks: Option<i64>,
+    /// visitors have to enter it, the env variable keeps it out of the process list
+    #[arg(long, env = "SHORTENER_LINK_PASSWORD", hide_env_values = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// only the signed url handed out works
+    #[arg(long)]
+    signed: bool,
+    /// 301, 302, 307 or 308
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect_status: Option<u16>,
+}
+
+/// Query of `GET /admin/urls`.
+#[derive(Debug, Args, Serialize)]
+struct ListArgs {
+    /// links per page, at most 200
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    /// next_cursor of the previous page
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+    /// case insensitive substring of the destination
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contains: Option<String>,
+    #[arg(long)]
+    include_deleted: bool,
+    /// follow the cursor to the last page
+    #[arg(long)]
+    #[serde(skip)]
+    all: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsQuery {
+    days: Option<u32>,
+    hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    id: String,
+    url: String,
+    clicks: i64,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListPage {
+    items: Vec<Value>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Stats {
+    id: String,
+    url: String,
+    total: i64,
+    daily: Vec<ClickBucket>,
+    hourly: Vec<ClickBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClickBucket {
+    start: DateTime<Utc>,
+    clicks: i64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let client = Client::new(cli.server, cli.token, cli.domain)?;
+    let format = cli.output;
+    match cli.command {
+        Command::Shorten(args) => {
+            let res = client.shorten(&args).await?;
+            print(format, &res, || {
+                let mut table = Table::new(&["URL"]);
+                table.row(vec![res["url"].as_str().unwrap_or_default().to_string()]);
+                Ok(table)
+            })
+        }
+        Command::Resolve { id } => {
+            let resolved = client.resolve(&id).await?;
+            print(format, &serde_json::to_value(&resolved)?, || {
+                let mut table = Table::new(&["ID", "STATUS", "LOCATION"]);
+                let location = resolved
+                    .location
+                    .clone()
+                    .unwrap_or_else(|| "(password protected)".to_string());
+                table.row(vec![
+                    resolved.id.clone(),
+                    resolved.status.to_string(),
+                    location,
+                ]);
+                Ok(table)
+            })
+        }
+        Command::List(args) => {
+            let res = list(&client, args).await?;
+            print(format, &res, || {
+                let page: ListPage = serde_json::from_value(res.clone())?;
+                if let Some(cursor) = &page.next_cursor {
+                    eprintln!("more links with --cursor {cursor}");
+                }
+                links_table(page.items)
+            })
+        }
+        Command::Delete { id } => {
+            let res = client.delete(id_of(&id)).await?;
+            print(format, &res, || links_table(vec![res.clone()]))
+        }
+        Command::Stats { id, days, hours } => {
+            let res = client
+                .stats(id_of(&id), &StatsQuery { days, hours })
+                .await?;
+            print(format, &res, || {
+                let stats: Stats = serde_json::from_value(res.clone())?;
+                println!("{} -> {}, {} clicks\n", stats.id, stats.url, stats.total);
+                let mut table = Table::new(&["BUCKET", "START", "CLICKS"]);
+                let daily = stats.daily.iter().map(|b| ("day", b));
+                let hourly = stats.hourly.iter().map(|b| ("hour", b));
+                for (bucket, b) in daily.chain(hourly) {
+                    table.row(vec![
+                        bucket.to_string(),
+                        b.start.format("%Y-%m-%d %H:%M").to_string(),
+                        b.clicks.to_string(),
+                    ]);
+                }
+                Ok(table)
+            })
+        }
+    }
+}
+
+/// One page, or with `--all` every page merged into one.
+async fn list(client: &Client, mut args: ListArgs) -> Result<Value> {
+    let mut page = client.list(&args).await?;
+    if !args.all {
+        return Ok(page);
+    }
+    let mut items = Vec::new();
+    loop {
+        let ListPage {
+            items: page_items,
+            next_cursor,
+        } = serde_json::from_value(page)?;
+        items.extend(page_items);
+        match next_cursor {
+            Some(cursor) => args.cursor = Some(cursor),
+            None => return Ok(json!({ "items": items, "next_cursor": null })),
+        }
+        page = client.list(&args).await?;
+    }
+}
+
+fn print(format: Format, json: &Value, table: impl FnOnce() -> Result<Table>) -> Result<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(json)?),
+        Format::Table => print!("{}", table()?.render()),
+    }
+    Ok(())
+}
+
+fn links_table(items: Vec<Value>) -> Result<Table> {
+    let time = |at: DateTime<Utc>| at.format("%Y-%m-%d %H:%M").to_string();
+    let mut table = Table::new(&["ID", "URL", "CLICKS", "CREATED", "EXPIRES", "DELETED"]);
+    for item in items {
+        let link: Link = serde_json::from_value(item)?;
+        table.row(vec![
+            link.id,
+            link.url,
+            link.clicks.to_string(),
+            time(link.created_at),
+            link.expires_at.map(time).unwrap_or_default(),
+            link.deleted_at.map(time).unwrap_or_default(),
+        ]);
+    }
+    Ok(table)
+}
+
+/// The id of `link`, which may be a whole short url.
+fn id_of(link: &str) -> &str {
+    match Url::parse(link) {
+        Ok(_) => link
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.trim_end_matches('/').rsplit('/').next())
+            .unwrap_or(link),
+        Err(_) => link,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_of_short_urls() {
+        assert_eq!(id_of("https://sho.rt/abc123"), "abc123");
+        assert_eq!(id_of("https://sho.rt/s/abc123/"), "abc123");
+        assert_eq!(id_of("https://sho.rt/abc123?exp=1&sig=x"), "abc123");
+        assert_eq!(id_of("http://localhost:9876/abc123#top"), "abc123");
+    }
+
+    #[test]
+    fn bare_ids_stay_as_they_are() {
+        assert_eq!(id_of("abc123"), "abc123");
+        assert_eq!(id_of("spring-sale"), "spring-sale");
+    }
+}
@@ -0,0 +1,94 @@
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// aligned columns for people
+    Table,
+    /// the server's json, for scripts
+    Json,
+}
+
+/// Plain text table, columns are padded to their widest cell.
+#[derive(Debug)]
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&'static str]) -> Self {
+        Self {
+            headers: headers.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        debug_assert_eq!(cells.len(), self.headers.len());
+        self.rows.push(cells);
+    }
+
+    pub fn render(&self) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let headers = self.headers.iter().map(|h| h.to_string()).collect();
+        let mut out = String::new();
+        for row in std::iter::once(&headers).chain(&self.rows) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect();
+            out.push_str(line.join("  ").trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_columns_to_the_widest_cell() {
+        let mut table = Table::new(&["ID", "URL", "CLICKS"]);
+        table.row(vec![
+            "abc".into(),
+            "https://example.com/".into(),
+            "3".into(),
+        ]);
+        table.row(vec![
+            "spring-sale".into(),
+            "https://a.io/".into(),
+            "12".into(),
+        ]);
+        assert_eq!(
+            table.render(),
+            "ID           URL                   CLICKS\n\
+             abc          https://example.com/  3\n\
+             spring-sale  https://a.io/         12\n"
+        );
+    }
+
+    #[test]
+    fn counts_chars_not_bytes() {
+        let mut table = Table::new(&["ID", "URL"]);
+        table.row(vec!["ünï".into(), "https://exämple.com/".into()]);
+        table.row(vec!["a".into(), "b".into()]);
+        assert_eq!(
+            table.render(),
+            "ID   URL\nünï  https://exämple.com/\na    b\n"
+        );
+    }
+
+    #[test]
+    fn renders_only_headers_without_rows() {
+        let table = Table::new(&["ID", "URL"]);
+        assert_eq!(table.render(), "ID  URL\n");
+    }
+}
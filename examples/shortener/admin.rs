@@ -0,0 +1,399 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::{bearer_token, Caller, Credentials},
+    domain::{Domain, RequestDomain},
+    error::ShortenerError,
+    store::{ListFilter, UrlRecord},
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct ListQuery {
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    /// 50 by default, at most 200
+    limit: Option<usize>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    /// substring of the destination url
+    contains: Option<String>,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct DeleteQuery {
+    /// remove the link and its clicks instead of soft deleting it
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ListRes {
+    items: Vec<UrlRecord>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateReq {
+    url: String,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SignReq {
+    /// defaults to `signed_url_ttl_secs` from now
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SignRes {
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Link management, every request needs `Authorization: Bearer <admin token or api key>`.
+/// Api keys only see the links they created, the admin token sees all of them.
+/// Like redirects, requests work on the links of the domain they are sent to.
+pub fn router(credentials: &Arc<Credentials>) -> Router<AppState> {
+    let router = Router::new()
+        .route("/urls", get(list_urls))
+        .route(
+            "/urls/:id",
+            get(get_url).patch(update_url).delete(delete_url),
+        )
+        .route("/urls/:id/restore", post(restore_url))
+        .route("/urls/:id/sign", post(sign_url));
+    if !credentials.has_admin() {
+        return router;
+    }
+    router.merge(admin_only(
+        Router::new().route("/cache", get(cache_stats)),
+        credentials.clone(),
+    ))
+}
+
+/// Require `Authorization: Bearer <admin token>` on every route of `router`.
+pub fn admin_only(router: Router<AppState>, credentials: Arc<Credentials>) -> Router<AppState> {
+    router.route_layer(middleware::from_fn_with_state(credentials, require_admin))
+}
+
+async fn require_admin(
+    State(credentials): State<Arc<Credentials>>,
+    headers: HeaderMap,
+    req: Request,
+    next: Next,
+) -> Result<Response, ShortenerError> {
+    let caller = headers
+        .get(AUTHORIZATION)
+        .and_then(bearer_token)
+        .and_then(|token| credentials.caller(token));
+    if !matches!(caller, Some(Caller::Admin)) {
+        return Err(ShortenerError::Unauthorized);
+    }
+    Ok(next.run(req).await)
+}
+
+/// List links, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/urls",
+    tag = "admin",
+    params(ListQuery),
+    responses(
+        (status = 200, body = ListRes),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 422, description = "invalid cursor", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn list_urls(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let owner = caller.scope()?.map(str::to_string);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+    let filter = ListFilter {
+        after,
+        created_after: query.created_after,
+        created_before: query.created_before,
+        contains: query.contains,
+        include_deleted: query.include_deleted,
+        owner,
+        domain: Some(domain.name.clone()),
+        // one extra row tells whether there is a next page
+        limit: limit + 1,
+    };
+    let mut items = state.store.list(&filter).await?;
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(encode_cursor)
+    } else {
+        None
+    };
+    Ok(Json(ListRes { items, next_cursor }))
+}
+
+/// Get a link
+#[utoipa::path(
+    get,
+    path = "/admin/urls/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "id of the link")),
+    responses(
+        (status = 200, body = UrlRecord),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "unknown link, or one of another owner", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn get_url(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    Ok(Json(owned_record(&state, &caller, &domain, &id).await?))
+}
+
+/// Change the destination of a link
+#[utoipa::path(
+    patch,
+    path = "/admin/urls/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "id of the link")),
+    request_body = UpdateReq,
+    responses(
+        (status = 200, body = UrlRecord),
+        (status = 422, description = "invalid url", body = ErrorBody),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "unknown link, or one of another owner", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn update_url(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Json(data): Json<UpdateReq>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    owned_record(&state, &caller, &domain, &id).await?;
+    let url = state.policy.check(&data.url)?;
+    Ok(Json(
+        state
+            .store
+            .update_url(&domain.key(&id), url.as_str())
+            .await?,
+    ))
+}
+
+/// Delete a link
+///
+/// Soft deleted links can be restored, `permanent=true` removes the link and its clicks.
+#[utoipa::path(
+    delete,
+    path = "/admin/urls/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "id of the link"), DeleteQuery),
+    responses(
+        (status = 200, description = "soft deleted", body = UrlRecord),
+        (status = 204, description = "permanently deleted"),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "unknown link, or one of another owner", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn delete_url(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Response, ShortenerError> {
+    owned_record(&state, &caller, &domain, &id).await?;
+    if query.permanent {
+        state.store.delete(&domain.key(&id)).await?;
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    Ok(Json(state.store.set_deleted(&domain.key(&id), true).await?).into_response())
+}
+
+/// Restore a soft deleted link
+#[utoipa::path(
+    post,
+    path = "/admin/urls/{id}/restore",
+    tag = "admin",
+    params(("id" = String, Path, description = "id of the link")),
+    responses(
+        (status = 200, body = UrlRecord),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "unknown link, or one of another owner", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn restore_url(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    owned_record(&state, &caller, &domain, &id).await?;
+    Ok(Json(
+        state.store.set_deleted(&domain.key(&id), false).await?,
+    ))
+}
+
+async fn cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    let stats = state
+        .cache
+        .as_ref()
+        .map(|cache| cache.stats())
+        .unwrap_or_default();
+    Json(stats)
+}
+
+/// Hand out a new url of a signed link, e.g. when the previous one expired.
+#[utoipa::path(
+    post,
+    path = "/admin/urls/{id}/sign",
+    tag = "admin",
+    params(("id" = String, Path, description = "id of the link")),
+    request_body = SignReq,
+    responses(
+        (status = 200, body = SignRes),
+        (status = 422, description = "not a signed link, or expires_at in the past", body = ErrorBody),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "unknown link, or one of another owner", body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+async fn sign_url(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Json(data): Json<SignReq>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let record = owned_record(&state, &caller, &domain, &id).await?;
+    if !record.signed {
+        return Err(ShortenerError::InvalidRequest(format!(
+            "{id} is not a signed link"
+        )));
+    }
+    let now = Utc::now();
+    let expires_at = data
+        .expires_at
+        .unwrap_or_else(|| now + state.config.signed_url_ttl());
+    if expires_at <= now {
+        return Err(ShortenerError::InvalidRequest(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+    let url = state.signed_url(&domain, &id, expires_at)?;
+    Ok(Json(SignRes { url, expires_at }))
+}
+
+/// The link `id` of `domain` if the caller may manage it. Links of other owners are
+/// reported as missing so their ids don't leak.
+async fn owned_record(
+    state: &AppState,
+    caller: &Caller,
+    domain: &Domain,
+    id: &str,
+) -> Result<UrlRecord, ShortenerError> {
+    let scope = caller.scope()?;
+    let record = state.store.get(&domain.key(id)).await?;
+    if scope.is_some_and(|owner| record.owner.as_deref() != Some(owner)) {
+        return Err(ShortenerError::NotFound);
+    }
+    Ok(record)
+}
+
+fn encode_cursor(record: &UrlRecord) -> String {
+    let created_at = record
+        .created_at
+        .to_rfc3339_opts(SecondsFormat::Micros, true);
+    URL_SAFE_NO_PAD.encode(format!("{created_at}|{}", record.id))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(DateTime<Utc>, String), ShortenerError> {
+    let invalid = || ShortenerError::InvalidRequest("invalid cursor".to_string());
+    let raw = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+    let (created_at, id) = raw.split_once('|').ok_or_else(invalid)?;
+    let created_at = DateTime::parse_from_rfc3339(created_at).map_err(|_| invalid())?;
+    Ok((created_at.to_utc(), id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(raw: &str) -> String {
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    #[test]
+    fn decodes_cursors() {
+        let (created_at, id) = decode_cursor(&cursor("2024-08-01T10:00:00.000001Z|abc")).unwrap();
+        assert_eq!(
+            created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            "2024-08-01T10:00:00.000001Z"
+        );
+        assert_eq!(id, "abc");
+    }
+
+    #[test]
+    fn decodes_cursors_in_utc() {
+        let (created_at, _) = decode_cursor(&cursor("2024-08-01T12:00:00+02:00|abc")).unwrap();
+        assert_eq!(
+            created_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "2024-08-01T10:00:00Z"
+        );
+    }
+
+    #[test]
+    fn ids_may_contain_the_separator() {
+        let (_, id) = decode_cursor(&cursor("2024-08-01T10:00:00Z|a|b")).unwrap();
+        assert_eq!(id, "a|b");
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        let invalid = |cursor: &str| {
+            matches!(
+                decode_cursor(cursor),
+                Err(ShortenerError::InvalidRequest(_))
+            )
+        };
+        assert!(invalid("not base64!"));
+        assert!(invalid(""));
+        assert!(invalid(&URL_SAFE_NO_PAD.encode([0xff, 0xfe, b'|'])));
+        assert!(invalid(&cursor("2024-08-01T10:00:00Z")));
+        assert!(invalid(&cursor("yesterday|abc")));
+        assert!(invalid(&cursor("2024-08-01|abc")));
+    }
+}
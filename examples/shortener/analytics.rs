@@ -0,0 +1,117 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use chrono::Utc;
+use http::{
+    header::{HeaderName, REFERER, USER_AGENT},
+    HeaderMap,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::store::{ClickEvent, LinkKey, UrlStore};
+
+const MAX_PENDING_CLICKS: usize = 4096;
+const MAX_BATCH: usize = 256;
+
+/// Hands click events to a background writer, so redirects never wait on the store.
+#[derive(Debug, Clone)]
+pub struct ClickRecorder {
+    /// the only sender, `close` drops it so the writer drains the queue and ends
+    tx: Arc<RwLock<Option<mpsc::Sender<ClickEvent>>>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    ip_key: [u8; 32],
+}
+
+impl ClickRecorder {
+    /// Spawn the writer task. Client ips are only kept as a blake3 hash keyed by `ip_salt`.
+    pub fn spawn(store: Arc<dyn UrlStore>, ip_salt: &str) -> Self {
+        let (tx, rx) = mpsc::channel(MAX_PENDING_CLICKS);
+        let writer = tokio::spawn(write_clicks(store, rx));
+        let ip_key = blake3::derive_key("shortener 2024-08 client ip hash", ip_salt.as_bytes());
+        Self {
+            tx: Arc::new(RwLock::new(Some(tx))),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            ip_key,
+        }
+    }
+
+    pub fn record(&self, key: &LinkKey, headers: &HeaderMap, ip: IpAddr) {
+        let header = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let event = ClickEvent {
+            domain: key.domain.clone(),
+            url_id: key.id.clone(),
+            clicked_at: Utc::now(),
+            referrer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            ip_hash: Some(self.hash_ip(ip)),
+        };
+        let tx = self.tx.read().unwrap_or_else(|e| e.into_inner());
+        let Some(tx) = tx.as_ref() else {
+            warn!("Failed to record click for {key}: shutting down");
+            return;
+        };
+        // drop the event rather than slow down the redirect when the writer falls behind
+        if let Err(e) = tx.try_send(event) {
+            warn!("Failed to record click for {key}: {e}");
+        }
+    }
+
+    /// Stop taking clicks and wait until the queued ones are written.
+    pub async fn close(&self) {
+        self.tx.write().unwrap_or_else(|e| e.into_inner()).take();
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = writer {
+            if let Err(e) = writer.await {
+                warn!("Click writer failed: {e}");
+            }
+        }
+    }
+
+    fn hash_ip(&self, ip: IpAddr) -> String {
+        blake3::keyed_hash(&self.ip_key, ip.to_string().as_bytes())
+            .to_hex()
+            .to_string()
+    }
+}
+
+async fn write_clicks(store: Arc<dyn UrlStore>, mut rx: mpsc::Receiver<ClickEvent>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while rx.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        if let Err(e) = store.record_clicks(&batch).await {
+            warn!("Failed to write {} clicks: {e}", batch.len());
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::store::{self, Bucket};
+
+    #[tokio::test]
+    async fn close_writes_the_queued_clicks() {
+        let store = store::open("memory://", false).await.unwrap();
+        let clicks = ClickRecorder::spawn(store.clone(), "salt");
+        let key = LinkKey::new("", "abc");
+        for _ in 0..3 {
+            clicks.record(&key, &HeaderMap::new(), IpAddr::from([192, 0, 2, 1]));
+        }
+        clicks.close().await;
+        // later clicks are dropped instead of queued for a writer that is gone
+        clicks.record(&key, &HeaderMap::new(), IpAddr::from([192, 0, 2, 1]));
+        let since = Utc::now() - TimeDelta::days(1);
+        let stats = store.click_stats(&key, Bucket::Day, since).await.unwrap();
+        assert_eq!(stats.iter().map(|b| b.clicks).sum::<i64>(), 3);
+    }
+}
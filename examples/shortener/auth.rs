@@ -0,0 +1,112 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{async_trait, extract::FromRequestParts};
+use http::{header::AUTHORIZATION, request::Parts, HeaderValue};
+
+use crate::{config::ApiKeyConfig, error::ShortenerError, AppState};
+
+/// Admin token and api keys, only their blake3 hashes are kept.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    admin_token: Option<blake3::Hash>,
+    api_keys: HashMap<blake3::Hash, Arc<ApiKey>>,
+}
+
+#[derive(Debug)]
+pub struct ApiKey {
+    pub owner: String,
+    pub max_links: Option<u64>,
+}
+
+/// Who sent the request, from `Authorization: Bearer <admin token or api key>`.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    Admin,
+    Key(Arc<ApiKey>),
+    Anonymous,
+}
+
+impl Credentials {
+    pub fn new(admin_token: Option<&str>, api_keys: &[ApiKeyConfig]) -> Self {
+        let api_keys = api_keys
+            .iter()
+            .map(|key| {
+                let api_key = ApiKey {
+                    owner: key.owner.clone(),
+                    max_links: key.max_links,
+                };
+                (blake3::hash(key.key.as_bytes()), Arc::new(api_key))
+            })
+            .collect();
+        Self {
+            admin_token: admin_token.map(|token| blake3::hash(token.as_bytes())),
+            api_keys,
+        }
+    }
+
+    /// Whether an admin token is configured, the admin only routes aren't served otherwise.
+    pub fn has_admin(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    pub fn caller(&self, token: &str) -> Option<Caller> {
+        let hash = blake3::hash(token.as_bytes());
+        // blake3::Hash compares in constant time
+        if self.admin_token == Some(hash) {
+            return Some(Caller::Admin);
+        }
+        self.api_keys.get(&hash).cloned().map(Caller::Key)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = ShortenerError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(AUTHORIZATION) else {
+            return Ok(Self::Anonymous);
+        };
+        // a wrong token is an error rather than an anonymous request, so typos don't go unnoticed
+        bearer_token(value)
+            .and_then(|token| state.credentials.caller(token))
+            .ok_or(ShortenerError::Unauthorized)
+    }
+}
+
+impl Caller {
+    /// Owner of the links the caller creates. Anonymous callers can only create
+    /// links while no api keys are configured.
+    pub fn creator(&self, credentials: &Credentials) -> Result<Option<String>, ShortenerError> {
+        match self {
+            Self::Admin => Ok(None),
+            Self::Key(key) => Ok(Some(key.owner.clone())),
+            Self::Anonymous if credentials.api_keys.is_empty() => Ok(None),
+            Self::Anonymous => Err(ShortenerError::Unauthorized),
+        }
+    }
+
+    /// Owner whose links the caller manages, `None` for every link.
+    pub fn scope(&self) -> Result<Option<&str>, ShortenerError> {
+        match self {
+            Self::Admin => Ok(None),
+            Self::Key(key) => Ok(Some(&key.owner)),
+            Self::Anonymous => Err(ShortenerError::Unauthorized),
+        }
+    }
+
+    pub fn max_links(&self) -> Option<(&str, u64)> {
+        match self {
+            Self::Key(key) => key.max_links.map(|max| (key.owner.as_str(), max)),
+            _ => None,
+        }
+    }
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token(value: &HeaderValue) -> Option<&str> {
+    value.to_str().ok()?.strip_prefix("Bearer ")
+}
@@ -0,0 +1,508 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::stream;
+use http::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderMap,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::types::Json as DbJson;
+use utoipa::ToSchema;
+
+use crate::{
+    admin,
+    auth::{Caller, Credentials},
+    destination,
+    domain::RequestDomain,
+    error::{ErrorBody, ShortenerError},
+    ratelimit::{limited, RateLimitLayer},
+    store::{ListFilter, RedirectStatus, UrlRecord, UrlStore},
+    AppState, ShortenReq, ALIAS_MAX_LEN,
+};
+
+const MAX_BULK_ITEMS: usize = 10_000;
+/// argon2 is slow on purpose, so password protected items are capped much lower
+const MAX_BULK_PASSWORDS: usize = 100;
+// 10k urls of up to 2KB each plus some slack
+const MAX_BULK_BODY: usize = 24 * 1024 * 1024;
+const EXPORT_PAGE_SIZE: usize = 500;
+
+const JSON: &str = "application/json";
+const CSV: &str = "text/csv";
+const NDJSON: &str = "application/x-ndjson";
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    #[default]
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// `UrlRecord` as a line of the ndjson export, with the password hash other responses leave out.
+#[derive(Debug, Serialize)]
+struct ExportRecord<'a> {
+    #[serde(flatten)]
+    record: &'a UrlRecord,
+    password_hash: &'a Option<String>,
+}
+
+/// `UrlRecord` as a csv row, csv has no nesting so destinations and query params are json text.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    id: String,
+    url: String,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<i64>,
+    clicks: i64,
+    created_at: DateTime<Utc>,
+    #[serde(default)]
+    last_clicked_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    password_hash: Option<String>,
+    #[serde(default)]
+    signed: bool,
+    owner: Option<String>,
+    #[serde(default)]
+    redirect_status: RedirectStatus,
+    destinations: Option<String>,
+    query_params: Option<String>,
+    #[serde(default)]
+    domain: String,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct BulkRes {
+    succeeded: usize,
+    failed: usize,
+    /// one entry per item, in upload order
+    results: Vec<ItemRes>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ItemRes {
+    index: usize,
+    /// the short url, or the id (`domain/id` off the default domain) for imports
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+/// `POST /bulk`, shortens a json array of `POST /` bodies or a csv with a
+/// `url,alias,expires_at,max_clicks` header where only `url` is required.
+pub fn router(limit: Option<&RateLimitLayer>) -> Router<AppState> {
+    Router::new()
+        .route("/bulk", limited(post(bulk_shorten), limit))
+        .layer(DefaultBodyLimit::max(MAX_BULK_BODY))
+}
+
+/// `GET /export` and `POST /import` of whole records, to move links between environments.
+/// They expose every link, so they need the admin token.
+pub fn transfer_router(credentials: Arc<Credentials>) -> Router<AppState> {
+    let router = Router::new()
+        .route("/export", get(export))
+        .route("/import", post(import))
+        .layer(DefaultBodyLimit::max(MAX_BULK_BODY));
+    admin::admin_only(router, credentials)
+}
+
+/// Shorten many urls
+///
+/// Every item succeeds or fails on its own, the response has a result per item.
+#[utoipa::path(
+    post,
+    path = "/bulk",
+    tag = "links",
+    request_body(
+        content = Vec<ShortenReq>,
+        description = "a json array of `POST /` bodies, or as `text/csv` a csv with a \
+            `url,alias,expires_at,max_clicks` header where only `url` is required",
+    ),
+    responses(
+        (status = 200, body = BulkRes),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "link quota exceeded", body = ErrorBody),
+        (status = 413, description = "body larger than 24 MiB"),
+        (status = 422, description = "malformed body, neither json nor csv, or more than 10000 items", body = ErrorBody),
+        (status = 429, description = "rate limited, see `Retry-After`", body = ErrorBody),
+    ),
+    security((), ("bearer" = [])),
+)]
+async fn bulk_shorten(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let owner = caller.creator(&state.credentials)?;
+    let items: Vec<Result<ShortenReq, ShortenerError>> = match content_type(&headers) {
+        JSON => parse_json_array(&body)?,
+        CSV => parse_csv(&body),
+        other => return Err(unsupported(other, &[JSON, CSV])),
+    };
+    check_len(items.len())?;
+
+    let now = Utc::now();
+    let items: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let mut req = item?;
+            state.check_request(&mut req, now)?;
+            Ok(req)
+        })
+        .collect();
+    check_passwords(&items)?;
+    let reqs: Vec<ShortenReq> = items
+        .iter()
+        .filter_map(|r| r.as_ref().ok())
+        .cloned()
+        .collect();
+    state.check_quota(&caller, reqs.len()).await?;
+    // one result per valid item, in the same order
+    let mut shortened = state.shorten_many(&reqs, owner, &domain).await?.into_iter();
+
+    let mut res = BulkRes::default();
+    for (index, item) in items.into_iter().enumerate() {
+        let result = item.and_then(|req| {
+            shortened
+                .next()
+                .expect("a result for every valid item")
+                .and_then(|id| state.share_url(&domain, &id, &req))
+        });
+        res.push(index, result);
+    }
+    Ok(Json(res))
+}
+
+async fn export(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let format = query.format;
+    let (content_type, filename) = match format {
+        ExportFormat::Csv => (CSV, "urls.csv"),
+        ExportFormat::Ndjson => (NDJSON, "urls.ndjson"),
+    };
+    // page through the store so large exports never sit in memory at once
+    let pages = stream::try_unfold(
+        (state.store.clone(), None, true),
+        move |(store, after, first)| async move {
+            let Some(page) = next_page(&store, after).await? else {
+                return Ok::<_, ShortenerError>(None);
+            };
+            let after = page
+                .last()
+                .map(|record| (record.created_at, record.id.clone()));
+            let chunk = match format {
+                ExportFormat::Csv => encode_csv(&page, first)?,
+                ExportFormat::Ndjson => encode_ndjson(&page)?,
+            };
+            Ok(Some((Bytes::from(chunk), (store, after, false))))
+        },
+    );
+    let headers = [
+        (CONTENT_TYPE, content_type.to_string()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ),
+    ];
+    (headers, Body::from_stream(pages))
+}
+
+/// Insert exported records as they are, ids that already exist are reported as conflicts.
+/// Their domains have to be configured on this instance.
+async fn import(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let items: Vec<Result<UrlRecord, ShortenerError>> = match content_type(&headers) {
+        NDJSON => parse_ndjson(&body),
+        CSV => parse_csv::<CsvRecord>(&body)
+            .into_iter()
+            .map(|row| row.and_then(UrlRecord::try_from))
+            .collect(),
+        other => return Err(unsupported(other, &[NDJSON, CSV])),
+    };
+    check_len(items.len())?;
+
+    let items: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let mut record = item?;
+            if !is_valid_id(&record.id) {
+                return Err(ShortenerError::InvalidRequest(format!(
+                    "invalid id: {}",
+                    record.id
+                )));
+            }
+            if state.domains.named(&record.domain).is_none() {
+                return Err(ShortenerError::InvalidRequest(format!(
+                    "unknown domain: {}",
+                    record.domain
+                )));
+            }
+            destination::validate(
+                record.destinations.as_deref().map(Vec::as_slice),
+                record.query_params.as_deref(),
+            )?;
+            record.url = state.policy.check(&record.url)?.into();
+            for destination in record.destinations.iter_mut().flat_map(|d| d.iter_mut()) {
+                destination.url = state.policy.check(&destination.url)?.into();
+            }
+            // listings and exports show the first destination as the link's url
+            if let Some(first) = record.destinations.as_ref().and_then(|d| d.first()) {
+                if first.url != record.url {
+                    return Err(ShortenerError::InvalidRequest(
+                        "url must be the url of the first destination".to_string(),
+                    ));
+                }
+            }
+            Ok(record)
+        })
+        .collect();
+    let records: Vec<UrlRecord> = items
+        .iter()
+        .filter_map(|r| r.as_ref().ok())
+        .cloned()
+        .collect();
+    let mut inserted = state.store.insert_many(&records).await?.into_iter();
+
+    let mut res = BulkRes::default();
+    for (index, item) in items.into_iter().enumerate() {
+        let result = item.and_then(|record| match inserted.next() {
+            Some(true) => Ok(record.key().to_string()),
+            Some(false) => Err(ShortenerError::Conflict(record.key().to_string())),
+            None => unreachable!("a result for every valid record"),
+        });
+        res.push(index, result);
+    }
+    Ok(Json(res))
+}
+
+impl BulkRes {
+    fn push(&mut self, index: usize, result: Result<String, ShortenerError>) {
+        let item = match result {
+            Ok(url) => {
+                self.succeeded += 1;
+                ItemRes {
+                    index,
+                    url: Some(url),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                self.failed += 1;
+                ItemRes {
+                    index,
+                    url: None,
+                    error: Some(e.body()),
+                }
+            }
+        };
+        self.results.push(item);
+    }
+}
+
+impl TryFrom<&UrlRecord> for CsvRecord {
+    type Error = ShortenerError;
+
+    fn try_from(record: &UrlRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: record.id.clone(),
+            url: record.url.clone(),
+            expires_at: record.expires_at,
+            max_clicks: record.max_clicks,
+            clicks: record.clicks,
+            created_at: record.created_at,
+            last_clicked_at: record.last_clicked_at,
+            deleted_at: record.deleted_at,
+            password_hash: record.password_hash.clone(),
+            signed: record.signed,
+            owner: record.owner.clone(),
+            redirect_status: record.redirect_status,
+            destinations: to_json_text(&record.destinations)?,
+            query_params: to_json_text(&record.query_params)?,
+            domain: record.domain.clone(),
+        })
+    }
+}
+
+impl TryFrom<CsvRecord> for UrlRecord {
+    type Error = ShortenerError;
+
+    fn try_from(row: CsvRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            destinations: from_json_text(row.destinations, "destinations")?,
+            query_params: from_json_text(row.query_params, "query_params")?,
+            id: row.id,
+            url: row.url,
+            expires_at: row.expires_at,
+            max_clicks: row.max_clicks,
+            clicks: row.clicks,
+            created_at: row.created_at,
+            last_clicked_at: row.last_clicked_at,
+            deleted_at: row.deleted_at,
+            password_hash: row.password_hash,
+            signed: row.signed,
+            owner: row.owner,
+            redirect_status: row.redirect_status,
+            domain: row.domain,
+        })
+    }
+}
+
+fn to_json_text<T: Serialize>(value: &Option<DbJson<T>>) -> Result<Option<String>, ShortenerError> {
+    value
+        .as_ref()
+        .map(|value| serde_json::to_string(value).map_err(|e| ShortenerError::Internal(e.into())))
+        .transpose()
+}
+
+fn from_json_text<T: DeserializeOwned>(
+    text: Option<String>,
+    column: &str,
+) -> Result<Option<DbJson<T>>, ShortenerError> {
+    text.map(|text| {
+        serde_json::from_str(&text)
+            .map_err(|e| ShortenerError::InvalidRequest(format!("invalid {column}: {e}")))
+    })
+    .transpose()
+}
+
+async fn next_page(
+    store: &Arc<dyn UrlStore>,
+    after: Option<(DateTime<Utc>, String)>,
+) -> Result<Option<Vec<UrlRecord>>, ShortenerError> {
+    let filter = ListFilter {
+        after,
+        include_deleted: true,
+        limit: EXPORT_PAGE_SIZE,
+        ..Default::default()
+    };
+    let page = store.list(&filter).await?;
+    Ok(Some(page).filter(|page| !page.is_empty()))
+}
+
+fn encode_csv(records: &[UrlRecord], header: bool) -> Result<Vec<u8>, ShortenerError> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(header)
+        .from_writer(Vec::new());
+    for record in records {
+        writer
+            .serialize(CsvRecord::try_from(record)?)
+            .map_err(|e| ShortenerError::Internal(e.into()))?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| ShortenerError::Internal(anyhow::anyhow!("flush csv: {e}")))
+}
+
+fn encode_ndjson(records: &[UrlRecord]) -> Result<Vec<u8>, ShortenerError> {
+    let mut buf = Vec::new();
+    for record in records {
+        let line = ExportRecord {
+            record,
+            password_hash: &record.password_hash,
+        };
+        serde_json::to_writer(&mut buf, &line).map_err(|e| ShortenerError::Internal(e.into()))?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+/// Media type of the body without parameters, json when not given.
+fn content_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(str::trim)
+        .unwrap_or(JSON)
+}
+
+fn unsupported(content_type: &str, supported: &[&str]) -> ShortenerError {
+    ShortenerError::InvalidRequest(format!(
+        "unsupported content type {content_type}, expected one of {}",
+        supported.join(", ")
+    ))
+}
+
+fn check_len(len: usize) -> Result<(), ShortenerError> {
+    if len > MAX_BULK_ITEMS {
+        return Err(ShortenerError::InvalidRequest(format!(
+            "at most {MAX_BULK_ITEMS} items per request, got {len}"
+        )));
+    }
+    Ok(())
+}
+
+fn check_passwords(items: &[Result<ShortenReq, ShortenerError>]) -> Result<(), ShortenerError> {
+    let passwords = items
+        .iter()
+        .filter(|item| item.as_ref().is_ok_and(|req| req.password.is_some()))
+        .count();
+    if passwords > MAX_BULK_PASSWORDS {
+        return Err(ShortenerError::InvalidRequest(format!(
+            "at most {MAX_BULK_PASSWORDS} password protected items per request, got {passwords}"
+        )));
+    }
+    Ok(())
+}
+
+/// A malformed body fails the request, a malformed item only fails that item.
+fn parse_json_array<T: DeserializeOwned>(
+    body: &[u8],
+) -> Result<Vec<Result<T, ShortenerError>>, ShortenerError> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| ShortenerError::InvalidRequest(format!("expected a json array: {e}")))?;
+    Ok(values
+        .into_iter()
+        .map(|v| serde_json::from_value(v).map_err(invalid_item))
+        .collect())
+}
+
+fn parse_ndjson<T: DeserializeOwned>(body: &[u8]) -> Vec<Result<T, ShortenerError>> {
+    body.split(|b| *b == b'\n')
+        .filter(|line| !line.trim_ascii().is_empty())
+        .map(|line| serde_json::from_slice(line).map_err(invalid_item))
+        .collect()
+}
+
+fn parse_csv<T: DeserializeOwned>(body: &[u8]) -> Vec<Result<T, ShortenerError>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body)
+        .into_deserialize()
+        .map(|row| row.map_err(invalid_item))
+        .collect()
+}
+
+fn invalid_item(e: impl ToString) -> ShortenerError {
+    ShortenerError::InvalidRequest(e.to_string())
+}
+
+/// Imported ids come from another instance, only make sure they are usable as a path.
+fn is_valid_id(id: &str) -> bool {
+    (1..=ALIAS_MAX_LEN).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
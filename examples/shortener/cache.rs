@@ -0,0 +1,282 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use lru::LruCache;
+use serde::Serialize;
+use tokio::time;
+use tracing::warn;
+
+use crate::store::{
+    Bucket, ClickBucket, ClickEvent, LinkKey, ListFilter, Migrate, NewUrl, StoreError, UrlRecord,
+    UrlStore,
+};
+
+const CLICK_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Read-through LRU cache in front of another store, only `resolve` is served from it.
+///
+/// Redirects of links without `max_clicks` are answered from memory and their clicks
+/// are added to the store in batches, so `clicks` may lag by a second. Links with a
+/// click limit still go through the store, which checks and counts them atomically.
+/// Ids the store doesn't know are cached as well, for the shorter `negative_ttl`.
+/// Writes through this store invalidate the link, other instances see them after `ttl`.
+#[derive(Debug)]
+pub struct CachedStore {
+    inner: Arc<dyn UrlStore>,
+    entries: Mutex<LruCache<LinkKey, Entry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    counters: Counters,
+    // link -> clicks served from the cache and not yet added to the store
+    pending_clicks: Arc<DashMap<LinkKey, i64>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// `None` when the store doesn't know the id
+    record: Option<UrlRecord>,
+    valid_until: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    /// hits on ids the store doesn't know
+    pub negative_hits: u64,
+    pub misses: u64,
+}
+
+impl CachedStore {
+    /// Wrap `inner` and spawn the task adding cached clicks to it.
+    pub fn new(
+        inner: Arc<dyn UrlStore>,
+        capacity: NonZeroUsize,
+        ttl: Duration,
+        negative_ttl: Duration,
+    ) -> Self {
+        let pending_clicks = Arc::new(DashMap::new());
+        tokio::spawn(flush_clicks(inner.clone(), pending_clicks.clone()));
+        Self {
+            inner,
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            negative_ttl,
+            counters: Counters::default(),
+            pending_clicks,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries();
+        CacheStats {
+            capacity: entries.cap().get(),
+            entries: entries.len(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            negative_hits: self.counters.negative_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, LruCache<LinkKey, Entry>> {
+        // the cache holds plain data, a panic elsewhere can't leave it half updated
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn cached(&self, key: &LinkKey) -> Option<Option<UrlRecord>> {
+        let mut entries = self.entries();
+        match entries.get(key) {
+            Some(entry) if entry.valid_until > Instant::now() => Some(entry.record.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Record of `key` from the cache or the store, `None` when the store doesn't know it.
+    async fn lookup(&self, key: &LinkKey) -> Result<Option<UrlRecord>, StoreError> {
+        if let Some(record) = self.cached(key) {
+            let counter = match record {
+                Some(_) => &self.counters.hits,
+                None => &self.counters.negative_hits,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            return Ok(record);
+        }
+
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let record = match self.inner.get(key).await {
+            Ok(record) => Some(record),
+            Err(StoreError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let ttl = match record {
+            Some(_) => self.ttl,
+            None => self.negative_ttl,
+        };
+        let entry = Entry {
+            record: record.clone(),
+            valid_until: Instant::now() + ttl,
+        };
+        self.entries().put(key.clone(), entry);
+        Ok(record)
+    }
+
+    fn invalidate(&self, key: &LinkKey) {
+        self.entries().pop(key);
+    }
+}
+
+#[async_trait]
+impl UrlStore for CachedStore {
+    async fn migrate(&self, to: Migrate) -> Result<()> {
+        self.inner.migrate(to).await?;
+        self.entries().clear();
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.inner.ping().await
+    }
+
+    async fn close(&self) {
+        // the flush task isn't waited for, so hand over what it hasn't written yet
+        add_pending_clicks(&*self.inner, &self.pending_clicks).await;
+        self.inner.close().await;
+    }
+
+    async fn shorten(&self, new: &NewUrl) -> Result<(), StoreError> {
+        self.inner.shorten(new).await?;
+        // the id may have been cached as unknown
+        self.invalidate(&LinkKey::new(&new.domain, &new.id));
+        Ok(())
+    }
+
+    async fn insert_many(&self, records: &[UrlRecord]) -> Result<Vec<bool>, StoreError> {
+        let inserted = self.inner.insert_many(records).await?;
+        let mut entries = self.entries();
+        for record in records {
+            entries.pop(&record.key());
+        }
+        Ok(inserted)
+    }
+
+    async fn resolve(&self, key: &LinkKey, unlocked: bool) -> Result<UrlRecord, StoreError> {
+        let record = self
+            .lookup(key)
+            .await?
+            .filter(|record| record.deleted_at.is_none())
+            .ok_or(StoreError::NotFound)?;
+        if record.max_clicks.is_some() {
+            return self.inner.resolve(key, unlocked).await;
+        }
+        if !record.is_alive(Utc::now()) {
+            return Err(StoreError::Expired);
+        }
+        if let Some(lock) = record.lock().filter(|_| !unlocked) {
+            return Err(StoreError::Locked(lock));
+        }
+        *self.pending_clicks.entry(record.key()).or_default() += 1;
+        Ok(record)
+    }
+
+    async fn get(&self, key: &LinkKey) -> Result<UrlRecord, StoreError> {
+        self.inner.get(key).await
+    }
+
+    async fn delete(&self, key: &LinkKey) -> Result<(), StoreError> {
+        let ret = self.inner.delete(key).await;
+        self.invalidate(key);
+        ret
+    }
+
+    async fn list(&self, filter: &ListFilter) -> Result<Vec<UrlRecord>, StoreError> {
+        self.inner.list(filter).await
+    }
+
+    async fn count_owned(&self, owner: &str) -> Result<u64, StoreError> {
+        self.inner.count_owned(owner).await
+    }
+
+    async fn update_url(&self, key: &LinkKey, url: &str) -> Result<UrlRecord, StoreError> {
+        let ret = self.inner.update_url(key, url).await;
+        self.invalidate(key);
+        ret
+    }
+
+    async fn set_deleted(&self, key: &LinkKey, deleted: bool) -> Result<UrlRecord, StoreError> {
+        let ret = self.inner.set_deleted(key, deleted).await;
+        self.invalidate(key);
+        ret
+    }
+
+    async fn purge_expired(&self, before: DateTime<Utc>) -> Result<u64, StoreError> {
+        let purged = self.inner.purge_expired(before).await?;
+        if purged > 0 {
+            self.entries().clear();
+        }
+        Ok(purged)
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), StoreError> {
+        self.inner.record_clicks(clicks).await
+    }
+
+    async fn add_clicks(&self, counts: &[(LinkKey, i64)]) -> Result<(), StoreError> {
+        self.inner.add_clicks(counts).await
+    }
+
+    async fn click_stats(
+        &self,
+        key: &LinkKey,
+        bucket: Bucket,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<ClickBucket>, StoreError> {
+        self.inner.click_stats(key, bucket, since).await
+    }
+}
+
+/// Periodically move the clicks counted in memory to the store.
+async fn flush_clicks(store: Arc<dyn UrlStore>, pending: Arc<DashMap<LinkKey, i64>>) {
+    let mut interval = time::interval(CLICK_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        add_pending_clicks(&*store, &pending).await;
+    }
+}
+
+/// Add the `pending` clicks to `store`, they are kept for the next round when that fails.
+async fn add_pending_clicks(store: &dyn UrlStore, pending: &DashMap<LinkKey, i64>) {
+    let keys: Vec<LinkKey> = pending.iter().map(|entry| entry.key().clone()).collect();
+    // clicks counted after a link is taken out start a new entry for the next round
+    let counts: Vec<(LinkKey, i64)> = keys.iter().filter_map(|key| pending.remove(key)).collect();
+    if counts.is_empty() {
+        return;
+    }
+    if let Err(e) = store.add_clicks(&counts).await {
+        warn!("Failed to add {} click counts: {e}", counts.len());
+        for (key, n) in counts {
+            *pending.entry(key).or_default() += n;
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::anyhow;
+use rand::Rng;
+use sqlx::types::Json;
+use url::Url;
+
+use crate::{
+    error::ShortenerError,
+    store::{Destination, QueryParams, UrlRecord},
+};
+
+pub const MAX_DESTINATIONS: usize = 10;
+pub const MAX_QUERY_PARAMS: usize = 20;
+const MAX_WEIGHT: u32 = 10_000;
+/// `{id}` is the short id, `{variant}` the 1-based position of the picked destination
+const PLACEHOLDERS: &[&str] = &["{id}", "{variant}"];
+
+/// The url a redirect of `record` goes to: one of its destinations picked by weight,
+/// with its query params filled in.
+pub fn render(record: &UrlRecord) -> Result<String, ShortenerError> {
+    render_variant(record, pick(record, &mut rand::thread_rng()))
+}
+
+/// Number of destinations of `record`, `variant`s run from 1 to it.
+pub fn variants(record: &UrlRecord) -> usize {
+    record
+        .destinations
+        .as_ref()
+        .map_or(1, |Json(destinations)| destinations.len().max(1))
+}
+
+/// The destination `variant` stands for, `record.url` when there is no such destination.
+fn destination_url(record: &UrlRecord, variant: usize) -> &str {
+    record
+        .destinations
+        .as_ref()
+        .and_then(|Json(destinations)| destinations.get(variant.checked_sub(1)?))
+        .map_or(&record.url, |destination| &destination.url)
+}
+
+/// The url of the `variant`th destination of `record` with its query params filled in.
+/// Parameters the destination already has are replaced, the others are kept as they are.
+pub fn render_variant(record: &UrlRecord, variant: usize) -> Result<String, ShortenerError> {
+    // stored records were validated, but an empty list must not take redirects down
+    let destination = destination_url(record, variant);
+    let Some(Json(params)) = &record.query_params else {
+        return Ok(destination.to_string());
+    };
+    let mut url = Url::parse(destination).map_err(|e| {
+        ShortenerError::Internal(anyhow!("stored url of {} is invalid: {e}", record.id))
+    })?;
+    let variant = variant.to_string();
+    let rendered = params
+        .iter()
+        .map(|(name, value)| (name, fill(value, &record.id, &variant)));
+    if url
+        .query_pairs()
+        .any(|(name, _)| params.contains_key(name.as_ref()))
+    {
+        let kept: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| !params.contains_key(name.as_ref()))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(kept)
+            .extend_pairs(rendered);
+    } else {
+        url.query_pairs_mut().extend_pairs(rendered);
+    }
+    Ok(url.into())
+}
+
+/// Check the destinations and query params of a new link.
+pub fn validate(
+    destinations: Option<&[Destination]>,
+    params: Option<&QueryParams>,
+) -> Result<(), ShortenerError> {
+    let invalid = |msg: String| Err(ShortenerError::InvalidRequest(msg));
+    if let Some(destinations) = destinations {
+        if !(2..=MAX_DESTINATIONS).contains(&destinations.len()) {
+            return invalid(format!(
+                "destinations must have 2-{MAX_DESTINATIONS} entries"
+            ));
+        }
+        if destinations.iter().any(|d| d.weight > MAX_WEIGHT) {
+            return invalid(format!("weights must be at most {MAX_WEIGHT}"));
+        }
+        if destinations.iter().all(|d| d.weight == 0) {
+            return invalid("at least one destination needs a positive weight".to_string());
+        }
+    }
+    if let Some(params) = params {
+        if params.len() > MAX_QUERY_PARAMS {
+            return invalid(format!("at most {MAX_QUERY_PARAMS} query params"));
+        }
+        if params.keys().any(|name| name.is_empty()) {
+            return invalid("query param names must not be empty".to_string());
+        }
+        for (name, value) in params {
+            let rest = fill(value, "", "");
+            if rest.contains('{') || rest.contains('}') {
+                return invalid(format!(
+                    "query param {name} has an unknown placeholder, expected {}",
+                    PLACEHOLDERS.join(" or ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 1-based variant of the destination to redirect to, 1 for links with a single one.
+fn pick(record: &UrlRecord, rng: &mut impl Rng) -> usize {
+    let Some(Json(destinations)) = &record.destinations else {
+        return 1;
+    };
+    // u64 so that weights of records that skipped validation can't overflow
+    let total: u64 = destinations.iter().map(|d| u64::from(d.weight)).sum();
+    let mut n = rng.gen_range(0..total.max(1));
+    for (i, destination) in destinations.iter().enumerate() {
+        let weight = u64::from(destination.weight);
+        if n < weight {
+            return i + 1;
+        }
+        n -= weight;
+    }
+    1
+}
+
+fn fill(template: &str, id: &str, variant: &str) -> String {
+    template.replace("{id}", id).replace("{variant}", variant)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn link(destinations: Option<&[(&str, u32)]>, query_params: &[(&str, &str)]) -> UrlRecord {
+        let destinations = destinations.map(|destinations| {
+            Json(
+                destinations
+                    .iter()
+                    .map(|(url, weight)| Destination {
+                        url: url.to_string(),
+                        weight: *weight,
+                    })
+                    .collect(),
+            )
+        });
+        UrlRecord {
+            id: "abc".to_string(),
+            url: "https://a.example/?x=1&utm_source=old".to_string(),
+            expires_at: None,
+            max_clicks: None,
+            clicks: 0,
+            created_at: Utc::now(),
+            last_clicked_at: None,
+            deleted_at: None,
+            password_hash: None,
+            signed: false,
+            owner: None,
+            redirect_status: Default::default(),
+            destinations,
+            query_params: (!query_params.is_empty()).then(|| Json(params(query_params))),
+            domain: String::new(),
+        }
+    }
+
+    const SPLIT: &[(&str, u32)] = &[
+        ("https://a.example/?x=1&utm_source=old", 1),
+        ("https://b.example/path", 3),
+    ];
+
+    #[test]
+    fn renders_the_picked_destination_with_its_params() {
+        let record = link(
+            Some(SPLIT),
+            &[("utm_source", "sho_{id}"), ("v", "{variant}")],
+        );
+        assert_eq!(
+            render_variant(&record, 1).unwrap(),
+            "https://a.example/?x=1&utm_source=sho_abc&v=1"
+        );
+        assert_eq!(
+            render_variant(&record, 2).unwrap(),
+            "https://b.example/path?utm_source=sho_abc&v=2"
+        );
+    }
+
+    #[test]
+    fn renders_destinations_verbatim_without_params() {
+        let record = link(Some(SPLIT), &[]);
+        assert_eq!(
+            render_variant(&record, 2).unwrap(),
+            "https://b.example/path"
+        );
+        let record = link(None, &[]);
+        assert_eq!(render(&record).unwrap(), record.url);
+    }
+
+    #[test]
+    fn unknown_variants_fall_back_to_the_url() {
+        let record = link(Some(&[]), &[("v", "{variant}")]);
+        assert_eq!(variants(&record), 1);
+        assert_eq!(
+            render(&record).unwrap(),
+            "https://a.example/?x=1&utm_source=old&v=1"
+        );
+        let record = link(Some(SPLIT), &[]);
+        assert_eq!(render_variant(&record, 0).unwrap(), record.url);
+        assert_eq!(render_variant(&record, 3).unwrap(), record.url);
+    }
+
+    #[test]
+    fn picks_by_weight() {
+        let mut rng = rand::thread_rng();
+        let record = link(
+            Some(&[("https://a.example/", 0), ("https://b.example/", 1)]),
+            &[],
+        );
+        assert!((0..100).all(|_| pick(&record, &mut rng) == 2));
+        let record = link(
+            Some(&[
+                ("https://a.example/", u32::MAX),
+                ("https://b.example/", u32::MAX),
+            ]),
+            &[],
+        );
+        assert!((0..100).all(|_| (1..=2).contains(&pick(&record, &mut rng))));
+        assert_eq!(pick(&link(None, &[]), &mut rng), 1);
+        assert_eq!(pick(&link(Some(&[]), &[]), &mut rng), 1);
+    }
+
+    fn destinations(weights: &[u32]) -> Vec<Destination> {
+        weights
+            .iter()
+            .map(|weight| Destination {
+                url: "https://example.com/".to_string(),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    fn params(params: &[(&str, &str)]) -> QueryParams {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn invalid(destinations: Option<&[Destination]>, params: Option<&QueryParams>) -> bool {
+        matches!(
+            validate(destinations, params),
+            Err(ShortenerError::InvalidRequest(_))
+        )
+    }
+
+    #[test]
+    fn validates_destinations() {
+        assert!(validate(Some(&destinations(&[1, 0])), None).is_ok());
+        assert!(validate(Some(&destinations(&[MAX_WEIGHT; MAX_DESTINATIONS])), None).is_ok());
+        assert!(invalid(Some(&destinations(&[])), None));
+        assert!(invalid(Some(&destinations(&[1])), None));
+        assert!(invalid(
+            Some(&destinations(&[1; MAX_DESTINATIONS + 1])),
+            None
+        ));
+        assert!(invalid(Some(&destinations(&[1, MAX_WEIGHT + 1])), None));
+        assert!(invalid(Some(&destinations(&[0, 0])), None));
+    }
+
+    #[test]
+    fn validates_query_params() {
+        assert!(validate(
+            None,
+            Some(&params(&[("ref", "{id}-{variant}"), ("src", "sho")]))
+        )
+        .is_ok());
+        assert!(invalid(None, Some(&params(&[("", "x")]))));
+        assert!(invalid(None, Some(&params(&[("ref", "{name}")]))));
+        assert!(invalid(None, Some(&params(&[("ref", "{id")]))));
+        assert!(invalid(None, Some(&params(&[("ref", "id}")]))));
+        let names: Vec<String> = (0..=MAX_QUERY_PARAMS).map(|i| format!("p{i}")).collect();
+        let many: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "x")).collect();
+        assert!(invalid(None, Some(&params(&many))));
+    }
+}
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Host},
+};
+use http::{header::HOST, request::Parts};
+use url::Url;
+
+use crate::{
+    config::{self, AppConfig},
+    error::ShortenerError,
+    store::LinkKey,
+    AppState,
+};
+
+/// A host links are served on. Every domain has its own ids.
+#[derive(Debug)]
+pub struct Domain {
+    /// stored with its links, empty for the default domain
+    pub name: String,
+    /// prefix of the short urls handed out
+    pub base_url: Url,
+    /// where unknown ids redirect to instead of answering 404
+    pub fallback_url: Option<Url>,
+}
+
+/// The default domain of `base_url` and the vanity domains, by host.
+#[derive(Debug)]
+pub struct Domains {
+    default: Arc<Domain>,
+    by_host: HashMap<String, Arc<Domain>>,
+}
+
+/// Domain of the `Host` of a request, or of its `X-Forwarded-Host` when a trusted proxy
+/// sent it.
+#[derive(Debug, Clone)]
+pub struct RequestDomain(pub Arc<Domain>);
+
+impl Domains {
+    pub fn new(config: &AppConfig) -> Result<Self> {
+        let default = Arc::new(Domain {
+            name: String::new(),
+            base_url: config.base_url.clone(),
+            fallback_url: config.fallback_url.clone(),
+        });
+        let mut by_host = HashMap::new();
+        for domain in &config.domains {
+            let domain = Domain {
+                name: domain.host.clone(),
+                base_url: domain.base_url(&config.base_url)?,
+                fallback_url: domain.fallback_url.clone(),
+            };
+            by_host.insert(domain.name.clone(), Arc::new(domain));
+        }
+        Ok(Self { default, by_host })
+    }
+
+    /// Domain requested as `host`, the default one for hosts that aren't configured.
+    /// A domain configured without a port also matches its host on any port.
+    pub fn get(&self, host: &str) -> Arc<Domain> {
+        let host = host.to_ascii_lowercase();
+        let without_port = match host.rsplit_once(':') {
+            // not the colons of an ipv6 address
+            Some((name, port)) if !name.ends_with(':') && port.parse::<u16>().is_ok() => name,
+            _ => &host,
+        };
+        self.by_host
+            .get(&host)
+            .or_else(|| self.by_host.get(without_port))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// The domain stored as `name`, e.g. in an import.
+    pub fn named(&self, name: &str) -> Option<Arc<Domain>> {
+        if name.is_empty() {
+            return Some(self.default.clone());
+        }
+        self.by_host.get(name).cloned()
+    }
+
+    /// Vanity domains and the hosts of their base urls, links to them would loop.
+    pub fn hosts(&self) -> impl Iterator<Item = String> + '_ {
+        self.by_host
+            .values()
+            .flat_map(|domain| [domain.name.clone(), config::authority(&domain.base_url)])
+    }
+}
+
+impl Domain {
+    pub fn key(&self, id: &str) -> LinkKey {
+        LinkKey::new(&self.name, id)
+    }
+
+    /// Short url of `id` on this domain.
+    pub fn short_url(&self, id: &str) -> Result<Url, ShortenerError> {
+        self.base_url
+            .join(id)
+            .map_err(|e| ShortenerError::Internal(e.into()))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequestDomain {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let trusted = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| state.proxies.is_trusted(addr.ip()));
+        // Host also reads the forwarded headers, which anyone else could make up
+        let host = if trusted {
+            Host::from_request_parts(parts, state)
+                .await
+                .map(|Host(host)| host)
+                .ok()
+        } else {
+            parts
+                .headers
+                .get(HOST)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+                .or_else(|| parts.uri.authority().map(|a| a.to_string()))
+        };
+        // http/1.0 clients may not send a host, they get the default domain
+        Ok(Self(state.domains.get(&host.unwrap_or_default())))
+    }
+}
@@ -0,0 +1,130 @@
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header::RETRY_AFTER, StatusCode};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::store::StoreError;
+
+#[derive(Debug, Error)]
+pub enum ShortenerError {
+    #[error("short url not found")]
+    NotFound,
+
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("id already taken: {0}")]
+    Conflict(String),
+
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+
+    #[error("short url expired or reached its click limit")]
+    Expired,
+
+    #[error("missing, expired or invalid link signature")]
+    InvalidSignature,
+
+    #[error("link quota of {0} exceeded")]
+    QuotaExceeded(u64),
+
+    #[error("too many requests, retry in {0}s")]
+    RateLimited(u64),
+
+    #[error("storage unavailable")]
+    StorageUnavailable(#[source] sqlx::Error),
+
+    #[error("internal error")]
+    Internal(#[source] anyhow::Error),
+}
+
+/// Body of every error response, e.g. `{"error": "not_found", "message": "short url not found"}`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// machine readable code, e.g. `not_found` or `rate_limited`
+    #[schema(value_type = String, example = "not_found")]
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl ShortenerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidUrl(_) | Self::InvalidRequest(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Expired => StatusCode::GONE,
+            Self::InvalidSignature | Self::QuotaExceeded(_) => StatusCode::FORBIDDEN,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::Conflict(_) => "conflict",
+            Self::Unauthorized => "unauthorized",
+            Self::Expired => "expired",
+            Self::InvalidSignature => "invalid_signature",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            Self::RateLimited(_) => "rate_limited",
+            Self::StorageUnavailable(_) => "storage_unavailable",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        }
+    }
+}
+
+impl From<StoreError> for ShortenerError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::NotFound => Self::NotFound,
+            StoreError::Conflict(id) => Self::Conflict(id),
+            StoreError::Expired => Self::Expired,
+            // callers unlock protected links before resolving them again
+            StoreError::Locked(_) => Self::Unauthorized,
+            StoreError::Database(
+                e @ (sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed),
+            ) => Self::StorageUnavailable(e),
+            StoreError::Database(e) => Self::Internal(e.into()),
+        }
+    }
+}
+
+impl IntoResponse for ShortenerError {
+    fn into_response(self) -> Response {
+        // the client only sees the summary, the cause goes to the log
+        match &self {
+            Self::StorageUnavailable(e) => warn!("{self}: {e}"),
+            Self::Internal(e) => error!("{self}: {e:#}"),
+            _ => {}
+        }
+        let mut res = (self.status(), Json(self.body())).into_response();
+        if let Self::RateLimited(secs) = self {
+            res.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        res
+    }
+}
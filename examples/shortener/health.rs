@@ -0,0 +1,43 @@
+use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use http::header::CACHE_CONTROL;
+use serde_json::json;
+
+use crate::{error::ShortenerError, store::StoreError, AppState};
+
+/// Probes of the orchestrator, not rate limited and without credentials.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// The process is up and serving, restart it when this fails.
+#[utoipa::path(get, path = "/healthz", tag = "health", responses((status = 200)))]
+async fn healthz() -> impl IntoResponse {
+    (
+        [(CACHE_CONTROL, "no-store")],
+        Json(json!({ "status": "ok" })),
+    )
+}
+
+/// The database is reachable, stop routing traffic here while this fails.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200),
+        (status = 503, description = "the database is unreachable", body = ErrorBody),
+    ),
+)]
+async fn readyz(State(state): State<AppState>) -> Result<impl IntoResponse, ShortenerError> {
+    match state.store.ping().await {
+        Ok(()) => Ok((
+            [(CACHE_CONTROL, "no-store")],
+            Json(json!({ "status": "ok" })),
+        )),
+        // any database error means not ready, not an internal error
+        Err(StoreError::Database(e)) => Err(ShortenerError::StorageUnavailable(e)),
+        Err(e) => Err(e.into()),
+    }
+}
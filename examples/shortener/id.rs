@@ -0,0 +1,160 @@
+use std::{collections::HashSet, io::Read, str::FromStr};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+const MIN_ID_LEN: usize = 4;
+const MAX_ID_LEN: usize = 32;
+/// attempts that derive hash ids, later ones are random so a link shortened many times
+/// with the same options still finds a free id
+const HASH_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    /// random nanoid
+    Random,
+    /// derived from the blake3 hash of the url and the link's options, so the same link
+    /// maps to the same id
+    Hash,
+}
+
+/// Produces candidate ids for `AppState::shorten`. Each retry after a collision asks for
+/// the next attempt, which is a fresh random id or the next id in the link's hash sequence.
+#[derive(Debug, Clone)]
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    length: usize,
+    alphabet: Vec<char>,
+}
+
+impl IdGenerator {
+    pub fn try_new(strategy: IdStrategy, length: usize, alphabet: &str) -> Result<Self> {
+        let alphabet: Vec<char> = alphabet.chars().collect();
+        if !(MIN_ID_LEN..=MAX_ID_LEN).contains(&length) {
+            bail!("id length must be between {MIN_ID_LEN} and {MAX_ID_LEN}");
+        }
+        if alphabet.len() < 2 || alphabet.len() > 256 {
+            bail!("id alphabet must have between 2 and 256 characters");
+        }
+        if alphabet.iter().collect::<HashSet<_>>().len() != alphabet.len() {
+            bail!("id alphabet must not contain duplicated characters");
+        }
+        if !alphabet
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        {
+            bail!("id alphabet may only contain [A-Za-z0-9_-]");
+        }
+        Ok(Self {
+            strategy,
+            length,
+            alphabet,
+        })
+    }
+
+    pub fn strategy(&self) -> IdStrategy {
+        self.strategy
+    }
+
+    /// `seed` identifies the link for hash ids, random ids ignore it.
+    pub fn generate(&self, seed: &str, attempt: u32) -> String {
+        match self.strategy {
+            IdStrategy::Hash if attempt < HASH_ATTEMPTS => self.hash_id(seed, attempt),
+            _ => nanoid::format(nanoid::rngs::default, &self.alphabet, self.length),
+        }
+    }
+
+    fn hash_id(&self, seed: &str, attempt: u32) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(seed.as_bytes());
+        if attempt > 0 {
+            hasher.update(&attempt.to_le_bytes());
+        }
+        let mut xof = hasher.finalize_xof();
+
+        // rejection sampling keeps every character equally likely
+        let n = self.alphabet.len();
+        let limit = 256 - 256 % n;
+        let mut id = String::with_capacity(self.length);
+        let mut byte = [0u8];
+        while id.len() < self.length {
+            xof.read_exact(&mut byte).expect("blake3 xof never ends");
+            let b = byte[0] as usize;
+            if b < limit {
+                id.push(self.alphabet[b % n]);
+            }
+        }
+        id
+    }
+}
+
+impl FromStr for IdStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(Self::Random),
+            "hash" => Ok(Self::Hash),
+            _ => bail!("unknown id strategy: {s}, expected random or hash"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_ids() -> IdGenerator {
+        IdGenerator::try_new(IdStrategy::Hash, 8, "0123456789abcdef").unwrap()
+    }
+
+    #[test]
+    fn hash_ids_are_deterministic() {
+        let ids = hash_ids();
+        let id = ids.generate("https://example.com/", 0);
+        assert_eq!(id, ids.generate("https://example.com/", 0));
+        assert_eq!(id, hash_ids().hash_id("https://example.com/", 0));
+        assert_eq!(id.len(), 8);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn hash_ids_differ_per_seed_and_attempt() {
+        let ids = hash_ids();
+        let first = ids.generate("https://example.com/", 0);
+        assert_ne!(first, ids.generate("https://example.com/", 1));
+        assert_ne!(first, ids.generate("https://example.org/", 0));
+        assert_eq!(
+            ids.generate("https://example.com/", 1),
+            ids.generate("https://example.com/", 1)
+        );
+    }
+
+    #[test]
+    fn hash_ids_turn_random_after_the_hash_attempts() {
+        let ids = hash_ids();
+        let a = ids.generate("https://example.com/", HASH_ATTEMPTS);
+        let b = ids.generate("https://example.com/", HASH_ATTEMPTS);
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 8);
+    }
+
+    #[test]
+    fn hash_ids_use_alphabets_of_any_size() {
+        // 3 doesn't divide 256, so some bytes are rejected
+        let ids = IdGenerator::try_new(IdStrategy::Hash, 32, "abc").unwrap();
+        let id = ids.generate("https://example.com/", 0);
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| "abc".contains(c)));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(IdGenerator::try_new(IdStrategy::Random, 3, "ab").is_err());
+        assert!(IdGenerator::try_new(IdStrategy::Random, 33, "ab").is_err());
+        assert!(IdGenerator::try_new(IdStrategy::Random, 6, "a").is_err());
+        assert!(IdGenerator::try_new(IdStrategy::Random, 6, "aba").is_err());
+        assert!(IdGenerator::try_new(IdStrategy::Random, 6, "ab/").is_err());
+    }
+}
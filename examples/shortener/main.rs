@@ -0,0 +1,1038 @@
+mod admin;
+mod analytics;
+mod auth;
+mod bulk;
+mod cache;
+mod config;
+mod destination;
+mod domain;
+mod error;
+mod health;
+mod id;
+mod openapi;
+mod policy;
+mod preview;
+mod protect;
+mod proxy;
+mod qr;
+mod ratelimit;
+mod store;
+
+use std::{env, future::IntoFuture, net::SocketAddr, num::NonZeroUsize, sync::Arc, time::Duration};
+
+use analytics::ClickRecorder;
+use anyhow::{anyhow, bail, Context, Result};
+use auth::{Caller, Credentials};
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use cache::CachedStore;
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+use config::{AppConfig, RateLimitConfig};
+use domain::{Domain, Domains, RequestDomain};
+use error::ShortenerError;
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use http::{
+    header::{CACHE_CONTROL, LOCATION},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use id::{IdGenerator, IdStrategy};
+use nanoid::nanoid;
+use policy::UrlPolicy;
+use protect::{SignatureQuery, UnlockReq, UrlSigner, MAX_PASSWORD_LEN};
+use proxy::{ClientIp, TrustedProxies};
+use ratelimit::{limited, RateLimitLayer};
+use serde::{Deserialize, Serialize};
+use store::{
+    Bucket, ClickBucket, Destination, LinkKey, Lock, Migrate, NewUrl, QueryParams, RedirectStatus,
+    StoreError, UrlRecord, UrlStore,
+};
+use tokio::{net::TcpListener, signal, time};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use utoipa::{IntoParams, ToSchema};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// how long closing the store may take after the requests are drained
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_STATS_DAYS: u32 = 30;
+const DEFAULT_STATS_HOURS: u32 = 24;
+const MAX_STATS_DAYS: u32 = 366;
+const MAX_STATS_HOURS: u32 = 24 * 7;
+const MAX_ID_ATTEMPTS: u32 = 5;
+/// argon2 hashes of a bulk upload computed at once, each keeps a blocking thread busy
+const PASSWORD_HASH_CONCURRENCY: usize = 4;
+const ALIAS_MIN_LEN: usize = 3;
+const ALIAS_MAX_LEN: usize = 32;
+// paths the service uses (or may use) itself, never handed out as aliases
+const RESERVED_ALIASES: &[&str] = &[
+    "admin", "api", "assets", "bulk", "docs", "export", "healthz", "import", "login", "logout",
+    "openapi", "readyz", "static", "stats", "swagger",
+];
+
+#[derive(Debug, Clone)]
+struct AppState {
+    config: Arc<AppConfig>,
+    store: Arc<dyn UrlStore>,
+    /// same store as `store` when the redirect cache is enabled, kept for its stats
+    cache: Option<Arc<CachedStore>>,
+    clicks: ClickRecorder,
+    ids: Arc<IdGenerator>,
+    policy: Arc<UrlPolicy>,
+    signer: Arc<UrlSigner>,
+    credentials: Arc<Credentials>,
+    domains: Arc<Domains>,
+    proxies: Arc<TrustedProxies>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+struct ShortenReq {
+    /// left out when `destinations` are given
+    #[serde(default)]
+    url: String,
+    /// weighted destinations rotated per redirect, e.g. for A/B tests
+    destinations: Option<Vec<Destination>>,
+    /// added to the destination on redirect, values may contain `{id}` and `{variant}`,
+    /// e.g. `{"utm_source": "newsletter", "utm_content": "{variant}"}`
+    #[schema(value_type = Option<BTreeMap<String, String>>)]
+    query_params: Option<QueryParams>,
+    /// vanity id such as `spring-sale`, a random id is generated when absent
+    alias: Option<String>,
+    /// the link answers 410 Gone after this instant
+    expires_at: Option<DateTime<Utc>>,
+    /// the link answers 410 Gone after this many redirects
+    max_clicks: Option<i64>,
+    /// visitors have to enter it before they are redirected
+    password: Option<String>,
+    /// the link only works through the signed url handed out, until `expires_at`
+    /// or for `signed_url_ttl_secs`
+    #[serde(default)]
+    signed: bool,
+    /// 301, 302 (the default), 307 or 308
+    #[serde(default)]
+    redirect_status: RedirectStatus,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ShortenRes {
+    url: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct StatsQuery {
+    /// number of daily buckets, ending today
+    days: Option<u32>,
+    /// number of hourly buckets, ending with the current hour
+    hours: Option<u32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct StatsRes {
+    id: String,
+    url: String,
+    total: i64,
+    daily: Vec<ClickBucket>,
+    hourly: Vec<ClickBucket>,
+}
+
+impl AppState {
+    async fn try_new(config: AppConfig) -> Result<Self> {
+        let mut store = store::open(&config.database_url, config.auto_migrate).await?;
+        let cache = NonZeroUsize::new(config.cache_capacity).map(|capacity| {
+            Arc::new(CachedStore::new(
+                store.clone(),
+                capacity,
+                Duration::from_secs(config.cache_ttl_secs),
+                Duration::from_secs(config.cache_negative_ttl_secs),
+            ))
+        });
+        if let Some(cache) = &cache {
+            store = cache.clone();
+        }
+        let ip_salt = config.ip_hash_salt.clone().unwrap_or_else(|| nanoid!(32));
+        let clicks = ClickRecorder::spawn(store.clone(), &ip_salt);
+        let ids = IdGenerator::try_new(config.id_strategy, config.id_length, &config.id_alphabet)?;
+        let signing_key = config.signing_key.clone().unwrap_or_else(|| {
+            warn!("No signing_key configured, signed urls stop working on restart");
+            nanoid!(32)
+        });
+
+        let mut policy = UrlPolicy::new(
+            config.allowed_domains.clone(),
+            config.blocked_domains.clone(),
+        )
+        .with_self_host(&config.listen_addr)
+        .with_self_host(&config.public_host());
+        if let Ok(addr) = config.listen_addr.parse::<SocketAddr>() {
+            policy = policy.with_self_host(&format!("localhost:{}", addr.port()));
+        }
+        let domains = Domains::new(&config)?;
+        for host in domains.hosts() {
+            policy = policy.with_self_host(&host);
+        }
+
+        Ok(Self {
+            store,
+            cache,
+            clicks,
+            ids: Arc::new(ids),
+            policy: Arc::new(policy),
+            signer: Arc::new(UrlSigner::new(&signing_key)),
+            credentials: Arc::new(Credentials::new(
+                config.admin_token.as_deref(),
+                &config.api_keys,
+            )),
+            domains: Arc::new(domains),
+            proxies: Arc::new(TrustedProxies::new(&config.trusted_proxies)?),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Short url of `id` on `domain` that works until `expires_at` even though the
+    /// link is signed.
+    fn signed_url(
+        &self,
+        domain: &Domain,
+        id: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<String, ShortenerError> {
+        let mut url = domain.short_url(id)?;
+        url.query_pairs_mut()
+            .extend_pairs(self.signer.sign(&domain.key(id), expires_at));
+        Ok(url.into())
+    }
+
+    /// The url handed out for the link `req` was shortened to.
+    fn share_url(
+        &self,
+        domain: &Domain,
+        id: &str,
+        req: &ShortenReq,
+    ) -> Result<String, ShortenerError> {
+        if !req.signed {
+            return Ok(domain.short_url(id)?.into());
+        }
+        let expires_at = req
+            .expires_at
+            .unwrap_or_else(|| Utc::now() + self.config.signed_url_ttl());
+        self.signed_url(domain, id, expires_at)
+    }
+
+    /// Fail when `caller` has a link quota that `new_links` more links would exceed.
+    /// Concurrent requests of one owner may overshoot it slightly.
+    async fn check_quota(&self, caller: &Caller, new_links: usize) -> Result<(), ShortenerError> {
+        let Some((owner, max)) = caller.max_links() else {
+            return Ok(());
+        };
+        if self.store.count_owned(owner).await? + new_links as u64 > max {
+            return Err(ShortenerError::QuotaExceeded(max));
+        }
+        Ok(())
+    }
+
+    /// Validate `req` and normalize its urls with the url policy.
+    fn check_request(
+        &self,
+        req: &mut ShortenReq,
+        now: DateTime<Utc>,
+    ) -> Result<(), ShortenerError> {
+        req.validate(now)?;
+        match &mut req.destinations {
+            Some(destinations) => {
+                for destination in destinations.iter_mut() {
+                    destination.url = self.policy.check(&destination.url)?.into();
+                }
+                // the first destination stands in for the link in listings and exports
+                req.url = destinations[0].url.clone();
+            }
+            None => req.url = self.policy.check(&req.url)?.into(),
+        }
+        Ok(())
+    }
+
+    async fn shorten(
+        &self,
+        req: &ShortenReq,
+        owner: Option<String>,
+        domain: &Domain,
+    ) -> Result<String, ShortenerError> {
+        let mut new = NewUrl {
+            id: String::new(),
+            url: req.url.clone(),
+            expires_at: req.expires_at,
+            max_clicks: req.max_clicks,
+            // microseconds is what every backend can store, and what list cursors carry
+            created_at: Utc::now().trunc_subsecs(6),
+            password_hash: req.password_hash().await?,
+            signed: req.signed,
+            owner,
+            redirect_status: req.redirect_status,
+            destinations: req.destinations.clone().map(sqlx::types::Json),
+            query_params: req.query_params.clone().map(sqlx::types::Json),
+            domain: domain.name.clone(),
+        };
+        if let Some(alias) = &req.alias {
+            new.id = alias.clone();
+            self.store.shorten(&new).await?;
+            return Ok(new.id);
+        }
+
+        let seed = id_seed(&new);
+        for attempt in 0..MAX_ID_ATTEMPTS {
+            new.id = self.ids.generate(&seed, attempt);
+            match self.store.shorten(&new).await {
+                Ok(()) => return Ok(new.id),
+                Err(StoreError::Conflict(id)) => {
+                    if let Some(existing) = self.reusable(&new).await? {
+                        return Ok(existing);
+                    }
+                    warn!("Id collision on {id}, attempt {}", attempt + 1);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ShortenerError::Internal(anyhow!(
+            "no free id after {MAX_ID_ATTEMPTS} attempts"
+        )))
+    }
+
+    /// Shorten a bulk upload, every round of new ids is inserted in one transaction.
+    /// Failures are reported per request, only storage errors fail the whole batch.
+    async fn shorten_many(
+        &self,
+        reqs: &[ShortenReq],
+        owner: Option<String>,
+        domain: &Domain,
+    ) -> Result<Vec<Result<String, ShortenerError>>, ShortenerError> {
+        let created_at = Utc::now().trunc_subsecs(6);
+        let password_hashes: Vec<Option<String>> = stream::iter(reqs)
+            .map(ShortenReq::password_hash)
+            .buffered(PASSWORD_HASH_CONCURRENCY)
+            .try_collect()
+            .await?;
+        let mut results: Vec<_> = reqs.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..reqs.len()).collect();
+        for attempt in 0..MAX_ID_ATTEMPTS {
+            if pending.is_empty() {
+                break;
+            }
+            let batch: Vec<NewUrl> = pending
+                .iter()
+                .map(|&i| {
+                    let req = &reqs[i];
+                    let mut new = NewUrl {
+                        id: String::new(),
+                        url: req.url.clone(),
+                        expires_at: req.expires_at,
+                        max_clicks: req.max_clicks,
+                        created_at,
+                        password_hash: password_hashes[i].clone(),
+                        signed: req.signed,
+                        owner: owner.clone(),
+                        redirect_status: req.redirect_status,
+                        destinations: req.destinations.clone().map(sqlx::types::Json),
+                        query_params: req.query_params.clone().map(sqlx::types::Json),
+                        domain: domain.name.clone(),
+                    };
+                    new.id = match &req.alias {
+                        Some(alias) => alias.clone(),
+                        None => self.ids.generate(&id_seed(&new), attempt),
+                    };
+                    new
+                })
+                .collect();
+            let records: Vec<UrlRecord> = batch.iter().cloned().map(UrlRecord::from).collect();
+            let inserted = self.store.insert_many(&records).await?;
+
+            let mut retry = Vec::new();
+            for ((i, new), inserted) in pending.into_iter().zip(batch).zip(inserted) {
+                if inserted {
+                    results[i] = Some(Ok(new.id));
+                } else if reqs[i].alias.is_some() {
+                    results[i] = Some(Err(ShortenerError::Conflict(new.id)));
+                } else if let Some(existing) = self.reusable(&new).await? {
+                    results[i] = Some(Ok(existing));
+                } else {
+                    warn!("Id collision on {}, attempt {}", new.id, attempt + 1);
+                    retry.push(i);
+                }
+            }
+            pending = retry;
+        }
+        let results = results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(ShortenerError::Internal(anyhow!(
+                        "no free id after {MAX_ID_ATTEMPTS} attempts"
+                    )))
+                })
+            })
+            .collect();
+        Ok(results)
+    }
+
+    /// With hash ids the same plain url always lands on the same id, hand out the
+    /// existing link instead of treating it as a collision.
+    async fn reusable(&self, new: &NewUrl) -> Result<Option<String>, StoreError> {
+        if self.ids.strategy() != IdStrategy::Hash {
+            return Ok(None);
+        }
+        let new = UrlRecord::from(new.clone());
+        if !new.is_plain() {
+            return Ok(None);
+        }
+        match self.store.get(&new.key()).await {
+            Ok(existing)
+                if existing.url == new.url
+                    && existing.owner == new.owner
+                    && existing.redirect_status == new.redirect_status
+                    && existing.deleted_at.is_none()
+                    && existing.is_plain() =>
+            {
+                Ok(Some(existing.id))
+            }
+            Ok(_) | Err(StoreError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resolve `key` for a visitor and count the click, `None` when the link asks for
+    /// a password first.
+    async fn get_url(
+        &self,
+        key: &LinkKey,
+        signature: &SignatureQuery,
+        password: Option<&str>,
+    ) -> Result<Option<UrlRecord>, ShortenerError> {
+        let lock = match self.store.resolve(key, false).await {
+            Ok(record) => return Ok(Some(record)),
+            Err(StoreError::Locked(lock)) => lock,
+            Err(e) => return Err(e.into()),
+        };
+        if !self.unlock(key, lock, signature, password).await? {
+            return Ok(None);
+        }
+        Ok(Some(self.store.resolve(key, true).await?))
+    }
+
+    /// Like `get_url` without counting a click, for HEAD requests and previews.
+    async fn peek_url(
+        &self,
+        key: &LinkKey,
+        signature: &SignatureQuery,
+        password: Option<&str>,
+    ) -> Result<Option<UrlRecord>, ShortenerError> {
+        let record = self.store.get(key).await?;
+        if record.deleted_at.is_some() {
+            return Err(ShortenerError::NotFound);
+        }
+        if !record.is_alive(Utc::now()) {
+            return Err(ShortenerError::Expired);
+        }
+        if let Some(lock) = record.lock() {
+            if !self.unlock(key, lock, signature, password).await? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Check the signature and password `lock` asks for, `false` when the password is missing.
+    async fn unlock(
+        &self,
+        key: &LinkKey,
+        lock: Lock,
+        signature: &SignatureQuery,
+        password: Option<&str>,
+    ) -> Result<bool, ShortenerError> {
+        if lock.signed {
+            self.signer.verify(key, signature, Utc::now())?;
+        }
+        if let Some(hash) = lock.password_hash {
+            let Some(password) = password else {
+                return Ok(false);
+            };
+            if !protect::verify_password(password, hash).await? {
+                return Err(ShortenerError::Unauthorized);
+            }
+        }
+        Ok(true)
+    }
+
+    /// `Cache-Control` of a redirect to `record`. Browsers may keep permanent redirects
+    /// for `permanent_redirect_max_age_secs`, unless the link can stop working earlier
+    /// than that or is protected.
+    fn cache_control(&self, record: &UrlRecord) -> String {
+        // a cached redirect would also pin the visitor to one destination
+        if !record.redirect_status.is_permanent()
+            || record.max_clicks.is_some()
+            || record.lock().is_some()
+            || record.destinations.is_some()
+        {
+            return "no-store".to_string();
+        }
+        let max_age = self.config.permanent_redirect_max_age_secs;
+        let max_age = match record.expires_at {
+            Some(expires_at) => (expires_at - Utc::now())
+                .num_seconds()
+                .clamp(0, max_age as i64) as u64,
+            None => max_age,
+        };
+        format!("public, max-age={max_age}")
+    }
+
+    async fn stats(
+        &self,
+        key: &LinkKey,
+        days: u32,
+        hours: u32,
+    ) -> Result<StatsRes, ShortenerError> {
+        let record = self.store.get(key).await?;
+        if record.deleted_at.is_some() {
+            return Err(ShortenerError::NotFound);
+        }
+        // the destination is what the lock protects
+        if record.lock().is_some() {
+            return Err(ShortenerError::Unauthorized);
+        }
+        let now = Utc::now();
+        let since = |bucket: Bucket, n: u32| bucket.trunc(now) - bucket.duration() * (n as i32 - 1);
+        let daily = self
+            .store
+            .click_stats(key, Bucket::Day, since(Bucket::Day, days))
+            .await?;
+        let hourly = self
+            .store
+            .click_stats(key, Bucket::Hour, since(Bucket::Hour, hours))
+            .await?;
+        Ok(StatsRes {
+            id: record.id,
+            url: record.url,
+            total: record.clicks,
+            daily,
+            hourly,
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let config = AppConfig::load()?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("migrate") => return migrate(&config, &args[1..]).await,
+        Some(cmd) => {
+            bail!("unknown command {cmd}, usage: shortener [migrate [up | down <version>]]")
+        }
+    }
+
+    let listen_addr = config.listen_addr.clone();
+    if config.admin_token.is_none() && config.api_keys.is_empty() {
+        info!("No admin_token or api_keys configured, anyone can create links");
+    }
+    info!("Connect to database: {}", config.database_url);
+    // bounded by `AppConfig::validate`
+    let retention = TimeDelta::seconds(config.purge_after_secs as i64);
+    let state = AppState::try_new(config).await?;
+    tokio::spawn(sweep_expired(state.store.clone(), retention));
+    let listener = TcpListener::bind(&listen_addr).await?;
+    info!("Listening on {listen_addr}");
+
+    let limits = &state.config.rate_limits;
+    let limit = |config: &Option<RateLimitConfig>| {
+        config.as_ref().map(|config| {
+            RateLimitLayer::new(config, state.credentials.clone(), state.proxies.clone())
+        })
+    };
+    let mut app = Router::new()
+        .route("/", limited(post(shorten), limit(&limits.shorten).as_ref()))
+        .route(
+            "/:id",
+            limited(
+                get(redirect).head(redirect_head),
+                limit(&limits.redirect).as_ref(),
+            )
+            .merge(limited(post(unlock), limit(&limits.unlock).as_ref())),
+        )
+        .route("/:id/stats", get(stats))
+        .route("/:id/qr", get(qr::qr_code));
+    app = app
+        .merge(health::router())
+        .merge(openapi::router())
+        .merge(bulk::router(limit(&limits.bulk).as_ref()))
+        .nest("/admin", admin::router(&state.credentials));
+    if state.credentials.has_admin() {
+        app = app.merge(bulk::transfer_router(state.credentials.clone()));
+    } else {
+        info!("No admin_token configured, export and import disabled");
+    }
+    let store = state.store.clone();
+    let clicks = state.clicks.clone();
+    let drain_timeout = Duration::from_secs(state.config.shutdown_timeout_secs);
+    let app = app.with_state(state);
+    let shutdown = shutdown_signal().shared();
+    // stops accepting connections on the signal, then waits for the open ones
+    let mut server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.clone())
+    .into_future();
+    tokio::select! {
+        res = &mut server => res?,
+        () = shutdown => {
+            info!("Shutting down, draining requests for up to {}s", drain_timeout.as_secs());
+            match time::timeout(drain_timeout, &mut server).await {
+                Ok(res) => res?,
+                Err(_) => warn!("Requests still running after {}s, closing anyway", drain_timeout.as_secs()),
+            }
+        }
+    }
+    // the clicks go to the store, so they are flushed before it closes
+    if time::timeout(CLOSE_TIMEOUT, clicks.close()).await.is_err() {
+        warn!("Clicks still being written, exiting without them");
+    }
+    if time::timeout(CLOSE_TIMEOUT, store.close()).await.is_err() {
+        warn!("Database connections still busy, exiting without closing them");
+    }
+    info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// Resolves on ctrl-c, or on the SIGTERM process managers send before killing us.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("install ctrl-c handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// `shortener migrate [up]` applies pending migrations, `shortener migrate down <version>`
+/// reverts the ones newer than `version`.
+async fn migrate(config: &AppConfig, args: &[String]) -> Result<()> {
+    let to = match args {
+        [] => Migrate::Up,
+        [cmd] if cmd == "up" => Migrate::Up,
+        [cmd, version] if cmd == "down" => Migrate::Down(
+            version
+                .parse()
+                .with_context(|| format!("invalid migration version: {version}"))?,
+        ),
+        _ => bail!("usage: shortener migrate [up | down <version>]"),
+    };
+    info!("Migrate {} {to:?}", config.database_url);
+    let store = store::open(&config.database_url, false).await?;
+    store.migrate(to).await?;
+    info!("Migrations done");
+    Ok(())
+}
+
+/// Shorten a url
+///
+/// Needs an api key or the admin token when the server has any configured.
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "links",
+    request_body = ShortenReq,
+    responses(
+        (status = 201, description = "the short url to hand out", body = ShortenRes),
+        (status = 401, description = "missing or invalid credentials", body = ErrorBody),
+        (status = 403, description = "link quota exceeded", body = ErrorBody),
+        (status = 409, description = "alias already taken", body = ErrorBody),
+        (status = 422, description = "invalid url or request", body = ErrorBody),
+        (status = 429, description = "rate limited, see `Retry-After`", body = ErrorBody),
+    ),
+    security((), ("bearer" = [])),
+)]
+// body的extract只能有一个，并且要放在最后，body只会解析一次
+async fn shorten(
+    State(state): State<AppState>,
+    caller: Caller,
+    RequestDomain(domain): RequestDomain,
+    Json(mut data): Json<ShortenReq>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let owner = caller.creator(&state.credentials)?;
+    state.check_request(&mut data, Utc::now())?;
+    state.check_quota(&caller, 1).await?;
+    let id = state.shorten(&data, owner, &domain).await?;
+    let body = Json(ShortenRes {
+        url: state.share_url(&domain, &id, &data)?,
+    });
+    Ok((StatusCode::CREATED, body))
+}
+
+/// Redirect to the destination
+///
+/// Counts a click. Password protected links answer an html form instead, and
+/// `/{id}+` answers a preview page of the destination.
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    tag = "redirects",
+    params(("id" = String, Path, description = "id of the link"), SignatureQuery),
+    responses(
+        (status = "3XX", description = "redirect with the status of the link, or to the fallback url of the domain",
+            headers(("location" = String, description = "the destination"))),
+        (status = 200, description = "unlock form of a password protected link", content_type = "text/html"),
+        (status = 403, description = "missing, expired or invalid signature", body = ErrorBody),
+        (status = 404, description = "unknown or deleted link", body = ErrorBody),
+        (status = 410, description = "expired or out of clicks", body = ErrorBody),
+    ),
+)]
+async fn redirect(
+    State(state): State<AppState>,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(signature): Query<SignatureQuery>,
+    ClientIp(ip): ClientIp,
+    req_headers: HeaderMap,
+) -> Result<Response, ShortenerError> {
+    if let Some(id) = id.strip_suffix('+') {
+        return preview(&state, &domain, id, &signature, None).await;
+    }
+    let key = domain.key(&id);
+    let record = match state.get_url(&key, &signature, None).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return Ok(protect::unlock_form(StatusCode::OK, None)),
+        Err(e) => return fallback(&domain, e),
+    };
+    state.clicks.record(&key, &req_headers, ip);
+    let cache_control = state.cache_control(&record);
+    redirect_to(
+        &record,
+        record.redirect_status.status_code(),
+        &cache_control,
+    )
+}
+
+/// Same headers as `redirect` without counting a click, so link checkers and unfurlers
+/// don't inflate the stats.
+#[utoipa::path(
+    head,
+    path = "/{id}",
+    tag = "redirects",
+    params(("id" = String, Path, description = "id of the link"), SignatureQuery),
+    responses(
+        (status = "3XX", description = "redirect with the status of the link",
+            headers(("location" = String, description = "the destination"))),
+        (status = 200, description = "the link is password protected"),
+        (status = 403, description = "missing, expired or invalid signature"),
+        (status = 404, description = "unknown or deleted link"),
+        (status = 410, description = "expired or out of clicks"),
+    ),
+)]
+async fn redirect_head(
+    State(state): State<AppState>,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(signature): Query<SignatureQuery>,
+) -> Result<Response, ShortenerError> {
+    if let Some(id) = id.strip_suffix('+') {
+        return preview(&state, &domain, id, &signature, None).await;
+    }
+    let record = match state.peek_url(&domain.key(&id), &signature, None).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return Ok(protect::unlock_form(StatusCode::OK, None)),
+        Err(e) => return fallback(&domain, e),
+    };
+    let cache_control = state.cache_control(&record);
+    redirect_to(
+        &record,
+        record.redirect_status.status_code(),
+        &cache_control,
+    )
+}
+
+/// `/:id+`, a page showing where the link goes instead of going there. The `+` can't
+/// be part of an id, and the router has no suffix matching, so `redirect` and
+/// `unlock` strip it.
+async fn preview(
+    state: &AppState,
+    domain: &Domain,
+    id: &str,
+    signature: &SignatureQuery,
+    password: Option<&str>,
+) -> Result<Response, ShortenerError> {
+    let Some(record) = state.peek_url(&domain.key(id), signature, password).await? else {
+        return Ok(protect::unlock_form(StatusCode::OK, None));
+    };
+    preview::page(domain.short_url(id)?.as_str(), &record)
+}
+
+/// Send visitors of ids `domain` doesn't know to its fallback url, when it has one.
+fn fallback(domain: &Domain, e: ShortenerError) -> Result<Response, ShortenerError> {
+    match (&domain.fallback_url, e) {
+        (Some(url), ShortenerError::NotFound) => {
+            let headers = [
+                (LOCATION, url.as_str()),
+                // the id may be taken any moment
+                (CACHE_CONTROL, "no-store"),
+            ];
+            Ok((StatusCode::FOUND, headers).into_response())
+        }
+        (_, e) => Err(e),
+    }
+}
+
+/// Form post of the unlock page of a password protected link.
+async fn unlock(
+    State(state): State<AppState>,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(signature): Query<SignatureQuery>,
+    ClientIp(ip): ClientIp,
+    req_headers: HeaderMap,
+    Form(form): Form<UnlockReq>,
+) -> Result<Response, ShortenerError> {
+    let password = Some(form.password.as_str());
+    let res = match id.strip_suffix('+') {
+        Some(id) => preview(&state, &domain, id, &signature, password).await,
+        None => state
+            .get_url(&domain.key(&id), &signature, password)
+            .await
+            .and_then(|record| {
+                let record = record.ok_or(ShortenerError::Unauthorized)?;
+                state.clicks.record(&record.key(), &req_headers, ip);
+                // 303 turns the post into a get of the destination
+                redirect_to(&record, StatusCode::SEE_OTHER, "no-store")
+            }),
+    };
+    match res {
+        Ok(res) => Ok(res),
+        Err(ShortenerError::Unauthorized) => Ok(protect::unlock_form(
+            StatusCode::UNAUTHORIZED,
+            Some("Wrong password, try again."),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn redirect_to(
+    record: &UrlRecord,
+    status: StatusCode,
+    cache_control: &str,
+) -> Result<Response, ShortenerError> {
+    let invalid = |e| {
+        ShortenerError::Internal(anyhow!(
+            "stored url of {} is not a valid header: {e}",
+            record.id
+        ))
+    };
+    let location = destination::render(record)?;
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, HeaderValue::from_str(&location).map_err(invalid)?);
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(cache_control).map_err(invalid)?,
+    );
+    Ok((status, headers).into_response())
+}
+
+/// Click counts of a link per day and hour
+#[utoipa::path(
+    get,
+    path = "/{id}/stats",
+    tag = "links",
+    params(("id" = String, Path, description = "id of the link"), StatsQuery),
+    responses(
+        (status = 200, body = StatsRes),
+        (status = 401, description = "the link is protected", body = ErrorBody),
+        (status = 404, description = "unknown or deleted link", body = ErrorBody),
+    ),
+)]
+async fn stats(
+    State(state): State<AppState>,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(query): Query<StatsQuery>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let days = query
+        .days
+        .unwrap_or(DEFAULT_STATS_DAYS)
+        .clamp(1, MAX_STATS_DAYS);
+    let hours = query
+        .hours
+        .unwrap_or(DEFAULT_STATS_HOURS)
+        .clamp(1, MAX_STATS_HOURS);
+    let stats = state.stats(&domain.key(&id), days, hours).await?;
+    Ok(Json(stats))
+}
+
+/// Periodically delete the urls that expired or were used up more than `retention` ago,
+/// until then they answer 410 and keep their clicks.
+async fn sweep_expired(store: Arc<dyn UrlStore>, retention: TimeDelta) {
+    let mut interval = time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match store.purge_expired(Utc::now() - retention).await {
+            Ok(0) => {}
+            Ok(n) => info!("Purged {n} expired urls"),
+            Err(e) => warn!("Failed to purge expired urls: {e}"),
+        }
+    }
+}
+
+impl ShortenReq {
+    fn validate(&self, now: DateTime<Utc>) -> Result<(), ShortenerError> {
+        let invalid = |msg: &str| Err(ShortenerError::InvalidRequest(msg.to_string()));
+        if self.url.is_empty() == self.destinations.is_none() {
+            return invalid("either url or destinations is required");
+        }
+        destination::validate(self.destinations.as_deref(), self.query_params.as_ref())?;
+        if self
+            .alias
+            .as_deref()
+            .is_some_and(|alias| !is_valid_alias(alias))
+        {
+            return invalid(
+                "alias must be 3-32 characters of [A-Za-z0-9_-] and not a reserved word",
+            );
+        }
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return invalid("expires_at must be in the future");
+        }
+        if self.max_clicks.is_some_and(|max| max <= 0) {
+            return invalid("max_clicks must be positive");
+        }
+        if self
+            .password
+            .as_ref()
+            .is_some_and(|p| p.is_empty() || p.len() > MAX_PASSWORD_LEN)
+        {
+            return invalid("password must be 1-128 bytes");
+        }
+        Ok(())
+    }
+
+    async fn password_hash(&self) -> Result<Option<String>, ShortenerError> {
+        match &self.password {
+            Some(password) => Ok(Some(protect::hash_password(password).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Aliases are 3-32 chars of `[A-Za-z0-9_-]` and must not shadow a reserved path.
+fn is_valid_alias(alias: &str) -> bool {
+    (ALIAS_MIN_LEN..=ALIAS_MAX_LEN).contains(&alias.len())
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !RESERVED_ALIASES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(alias))
+}
+
+/// Hash ids derive from the domain, the url, the owner and a digest of the link's options,
+/// so links to one url by different owners or with different options don't contend for the
+/// same few ids.
+fn id_seed(new: &NewUrl) -> String {
+    // the hash itself is salted, only whether there is one tells links apart
+    let options = format!(
+        "{:?} {:?} {} {} {:?} {:?} {:?}",
+        new.expires_at,
+        new.max_clicks,
+        new.password_hash.is_some(),
+        new.signed,
+        new.redirect_status,
+        new.destinations,
+        new.query_params
+    );
+    format!(
+        "{} {} {:?} {}",
+        new.domain,
+        new.url,
+        new.owner,
+        blake3::hash(options.as_bytes())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn aliases_are_short_ascii_words() {
+        assert!(is_valid_alias("abc"));
+        assert!(is_valid_alias("Spring-Sale_2024"));
+        assert!(is_valid_alias(&"a".repeat(ALIAS_MAX_LEN)));
+        assert!(!is_valid_alias("ab"));
+        assert!(!is_valid_alias(&"a".repeat(ALIAS_MAX_LEN + 1)));
+        assert!(!is_valid_alias("a b"));
+        assert!(!is_valid_alias("a/b"));
+        assert!(!is_valid_alias("a+b"));
+        assert!(!is_valid_alias("héllo"));
+    }
+
+    #[test]
+    fn aliases_never_shadow_reserved_paths() {
+        assert!(!is_valid_alias("admin"));
+        assert!(!is_valid_alias("Admin"));
+        assert!(!is_valid_alias("HEALTHZ"));
+        assert!(is_valid_alias("admins"));
+    }
+
+    async fn hash_state() -> AppState {
+        let config = AppConfig {
+            database_url: "memory://".to_string(),
+            id_strategy: IdStrategy::Hash,
+            id_alphabet: "0123456789abcdef".to_string(),
+            ..Default::default()
+        };
+        AppState::try_new(config).await.unwrap()
+    }
+
+    fn req(json: serde_json::Value) -> ShortenReq {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hash_ids_reuse_plain_links() {
+        let state = hash_state().await;
+        let domain = state.domains.named("").unwrap();
+        let plain = req(serde_json::json!({ "url": "https://example.com/" }));
+        let id = state.shorten(&plain, None, &domain).await.unwrap();
+        assert_eq!(id, state.shorten(&plain, None, &domain).await.unwrap());
+        let team = Some("team".to_string());
+        let team_id = state.shorten(&plain, team.clone(), &domain).await.unwrap();
+        assert_ne!(id, team_id);
+        assert_eq!(team_id, state.shorten(&plain, team, &domain).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn hash_ids_run_out_into_random_ids() {
+        let state = hash_state().await;
+        let domain = state.domains.named("").unwrap();
+        let limited = req(serde_json::json!({ "url": "https://example.com/", "max_clicks": 10 }));
+        let mut ids = HashSet::new();
+        for _ in 0..MAX_ID_ATTEMPTS * 2 {
+            assert!(ids.insert(state.shorten(&limited, None, &domain).await.unwrap()));
+        }
+        let other = req(serde_json::json!({ "url": "https://example.com/", "max_clicks": 20 }));
+        assert!(ids.insert(state.shorten(&other, None, &domain).await.unwrap()));
+    }
+}
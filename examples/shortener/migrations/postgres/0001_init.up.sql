@@ -0,0 +1,30 @@
+-- tables created before migrations existed are adopted and brought up to date
+CREATE TABLE IF NOT EXISTS urls (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    expires_at TIMESTAMPTZ,
+    max_clicks BIGINT,
+    clicks BIGINT NOT NULL DEFAULT 0,
+    last_clicked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deleted_at TIMESTAMPTZ
+);
+ALTER TABLE urls ALTER COLUMN id TYPE TEXT;
+ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key;
+ALTER TABLE urls
+    ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS max_clicks BIGINT,
+    ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS last_clicked_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS urls_created_at_id ON urls (created_at, id);
+
+CREATE TABLE IF NOT EXISTS clicks (
+    url_id TEXT NOT NULL,
+    clicked_at TIMESTAMPTZ NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    ip_hash TEXT
+);
+CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
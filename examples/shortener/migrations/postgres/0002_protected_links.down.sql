@@ -0,0 +1,3 @@
+ALTER TABLE urls
+    DROP COLUMN password_hash,
+    DROP COLUMN signed;
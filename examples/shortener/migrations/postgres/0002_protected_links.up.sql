@@ -0,0 +1,3 @@
+ALTER TABLE urls
+    ADD COLUMN password_hash TEXT,
+    ADD COLUMN signed BOOLEAN NOT NULL DEFAULT FALSE;
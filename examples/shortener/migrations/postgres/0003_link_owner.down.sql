@@ -0,0 +1,2 @@
+DROP INDEX urls_owner_created_at_id;
+ALTER TABLE urls DROP COLUMN owner;
@@ -0,0 +1,3 @@
+ALTER TABLE urls
+    DROP COLUMN destinations,
+    DROP COLUMN query_params;
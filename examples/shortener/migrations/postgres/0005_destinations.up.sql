@@ -0,0 +1,3 @@
+ALTER TABLE urls
+    ADD COLUMN destinations JSONB,
+    ADD COLUMN query_params JSONB;
@@ -0,0 +1,11 @@
+-- ids are only unique without the other domains, so their links are dropped
+DELETE FROM clicks WHERE domain <> '';
+DELETE FROM urls WHERE domain <> '';
+
+DROP INDEX clicks_domain_url_id_clicked_at;
+CREATE INDEX clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
+ALTER TABLE clicks DROP COLUMN domain;
+
+ALTER TABLE urls DROP CONSTRAINT urls_pkey;
+ALTER TABLE urls ADD PRIMARY KEY (id);
+ALTER TABLE urls DROP COLUMN domain;
@@ -0,0 +1,8 @@
+-- every domain has its own ids, existing links belong to the default domain ''
+ALTER TABLE urls ADD COLUMN domain TEXT NOT NULL DEFAULT '';
+ALTER TABLE urls DROP CONSTRAINT urls_pkey;
+ALTER TABLE urls ADD PRIMARY KEY (domain, id);
+
+ALTER TABLE clicks ADD COLUMN domain TEXT NOT NULL DEFAULT '';
+DROP INDEX clicks_url_id_clicked_at;
+CREATE INDEX clicks_domain_url_id_clicked_at ON clicks (domain, url_id, clicked_at);
@@ -0,0 +1,21 @@
+-- timestamps are rfc3339 text in utc, so they compare as strings
+CREATE TABLE IF NOT EXISTS urls (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    expires_at TEXT,
+    max_clicks INTEGER,
+    clicks INTEGER NOT NULL DEFAULT 0,
+    last_clicked_at TEXT,
+    created_at TEXT NOT NULL,
+    deleted_at TEXT
+);
+CREATE INDEX IF NOT EXISTS urls_created_at_id ON urls (created_at, id);
+
+CREATE TABLE IF NOT EXISTS clicks (
+    url_id TEXT NOT NULL,
+    clicked_at TEXT NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    ip_hash TEXT
+);
+CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks (url_id, clicked_at);
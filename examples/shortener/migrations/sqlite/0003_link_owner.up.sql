@@ -0,0 +1,2 @@
+ALTER TABLE urls ADD COLUMN owner TEXT;
+CREATE INDEX urls_owner_created_at_id ON urls (owner, created_at, id);
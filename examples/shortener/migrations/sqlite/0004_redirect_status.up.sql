@@ -0,0 +1 @@
+ALTER TABLE urls ADD COLUMN redirect_status INTEGER NOT NULL DEFAULT 302 CHECK (redirect_status IN (301, 302, 307, 308));
@@ -0,0 +1,2 @@
+ALTER TABLE urls DROP COLUMN destinations;
+ALTER TABLE urls DROP COLUMN query_params;
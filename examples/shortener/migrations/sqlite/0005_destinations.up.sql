@@ -0,0 +1,2 @@
+ALTER TABLE urls ADD COLUMN destinations TEXT;
+ALTER TABLE urls ADD COLUMN query_params TEXT;
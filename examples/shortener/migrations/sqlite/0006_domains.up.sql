@@ -0,0 +1,29 @@
+-- every domain has its own ids, existing links belong to the default domain ''.
+-- sqlite can't change a primary key in place, so the table is rebuilt
+CREATE TABLE urls_new (
+    id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    expires_at TEXT,
+    max_clicks INTEGER,
+    clicks INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    deleted_at TEXT,
+    password_hash TEXT,
+    signed INTEGER NOT NULL DEFAULT 0,
+    owner TEXT,
+    redirect_status INTEGER NOT NULL DEFAULT 302 CHECK (redirect_status IN (301, 302, 307, 308)),
+    destinations TEXT,
+    query_params TEXT,
+    domain TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (domain, id)
+);
+INSERT INTO urls_new (id, url, expires_at, max_clicks, clicks, created_at, deleted_at, password_hash, signed, owner, redirect_status, destinations, query_params)
+    SELECT id, url, expires_at, max_clicks, clicks, created_at, deleted_at, password_hash, signed, owner, redirect_status, destinations, query_params FROM urls;
+DROP TABLE urls;
+ALTER TABLE urls_new RENAME TO urls;
+CREATE INDEX urls_created_at_id ON urls (created_at, id);
+CREATE INDEX urls_owner_created_at_id ON urls (owner, created_at, id);
+
+ALTER TABLE clicks ADD COLUMN domain TEXT NOT NULL DEFAULT '';
+DROP INDEX clicks_url_id_clicked_at;
+CREATE INDEX clicks_domain_url_id_clicked_at ON clicks (domain, url_id, clicked_at);
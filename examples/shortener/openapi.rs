@@ -0,0 +1,85 @@
+use axum::Router;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    admin, bulk,
+    error::ErrorBody,
+    health, qr,
+    store::{ClickBucket, Destination, RedirectStatus, UrlRecord},
+    AppState, ShortenReq, ShortenRes, StatsRes,
+};
+
+/// The json api, to generate clients from. Pages for people (unlock forms, previews)
+/// and the admin export and import are left out.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "shortener",
+        description = "Short links with click stats. Requests work on the links of the domain \
+            in their `Host` header, the default domain unless it is a configured vanity domain."
+    ),
+    paths(
+        crate::shorten,
+        bulk::bulk_shorten,
+        crate::redirect,
+        crate::redirect_head,
+        crate::stats,
+        qr::qr_code,
+        admin::list_urls,
+        admin::get_url,
+        admin::update_url,
+        admin::delete_url,
+        admin::restore_url,
+        admin::sign_url,
+        health::healthz,
+        health::readyz,
+    ),
+    components(schemas(
+        ShortenReq,
+        ShortenRes,
+        StatsRes,
+        ClickBucket,
+        Destination,
+        RedirectStatus,
+        UrlRecord,
+        ErrorBody,
+        bulk::BulkRes,
+        bulk::ItemRes,
+        admin::ListRes,
+        admin::UpdateReq,
+        admin::SignReq,
+        admin::SignRes,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "links", description = "shorten urls and look at them"),
+        (name = "redirects", description = "what visitors of short urls get"),
+        (name = "admin", description = "link management, api keys only see their own links"),
+        (name = "health", description = "probes of the orchestrator"),
+    )
+)]
+struct ApiDoc;
+
+/// `Authorization: Bearer <admin token or api key>`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// `GET /openapi.json` and the swagger ui on top of it at `/docs`.
+pub fn router() -> Router<AppState> {
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
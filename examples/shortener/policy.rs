@@ -0,0 +1,185 @@
+use url::Url;
+
+use crate::error::ShortenerError;
+
+const MAX_URL_LEN: usize = 2048;
+const ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+
+/// Rules a destination must pass before it is shortened.
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    /// when not empty, only these domains and their subdomains can be shortened
+    allowed_domains: Vec<String>,
+    blocked_domains: Vec<String>,
+    /// host and optional port the shortener itself is reachable at, links back to it would loop
+    self_hosts: Vec<(String, Option<u16>)>,
+}
+
+impl UrlPolicy {
+    pub fn new(allowed_domains: Vec<String>, blocked_domains: Vec<String>) -> Self {
+        let normalize = |domains: Vec<String>| {
+            domains
+                .into_iter()
+                .map(|d| d.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect()
+        };
+        Self {
+            allowed_domains: normalize(allowed_domains),
+            blocked_domains: normalize(blocked_domains),
+            self_hosts: Vec::new(),
+        }
+    }
+
+    /// Refuse urls pointing at `authority` (`host` or `host:port`).
+    pub fn with_self_host(mut self, authority: &str) -> Self {
+        if let Ok(url) = Url::parse(&format!("http://{authority}")) {
+            if let Some(host) = url.host_str() {
+                // the parser drops :80 as the default port of http
+                let port = url.port().or(authority.ends_with(":80").then_some(80));
+                self.self_hosts.push((host.to_string(), port));
+            }
+        }
+        self
+    }
+
+    /// Parse and check `url`, returning it in normalized form.
+    pub fn check(&self, url: &str) -> Result<Url, ShortenerError> {
+        let invalid = |msg: String| Err(ShortenerError::InvalidUrl(msg));
+        if url.len() > MAX_URL_LEN {
+            return invalid(format!("url is longer than {MAX_URL_LEN} bytes"));
+        }
+        // the parser silently drops tabs and newlines, reject them before it sees them
+        if url.chars().any(char::is_control) {
+            return invalid("url contains control characters".to_string());
+        }
+        let parsed = match Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => return invalid(e.to_string()),
+        };
+        if !ALLOWED_SCHEMES.contains(&parsed.scheme()) {
+            return invalid(format!("scheme {} is not allowed", parsed.scheme()));
+        }
+        if !parsed.username().is_empty() || parsed.password().is_some() {
+            return invalid("url must not contain credentials".to_string());
+        }
+        let Some(host) = parsed.host_str() else {
+            return invalid("url has no host".to_string());
+        };
+        let host = host.trim_end_matches('.');
+
+        if self.points_to_self(host, parsed.port_or_known_default()) {
+            return invalid("url points back to the shortener".to_string());
+        }
+        if self.blocked_domains.iter().any(|d| matches_domain(host, d)) {
+            return invalid(format!("domain {host} is blocked"));
+        }
+        if !self.allowed_domains.is_empty()
+            && !self.allowed_domains.iter().any(|d| matches_domain(host, d))
+        {
+            return invalid(format!("domain {host} is not allowed"));
+        }
+        Ok(parsed)
+    }
+
+    fn points_to_self(&self, host: &str, port: Option<u16>) -> bool {
+        self.self_hosts
+            .iter()
+            .any(|(h, p)| h == host && (p.is_none() || *p == port))
+    }
+}
+
+/// `host` is `domain` itself or one of its subdomains.
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> UrlPolicy {
+        UrlPolicy::new(Vec::new(), vec![".Evil.com ".to_string()]).with_self_host("sho.rt:8080")
+    }
+
+    fn rejected(policy: &UrlPolicy, url: &str) -> bool {
+        matches!(policy.check(url), Err(ShortenerError::InvalidUrl(_)))
+    }
+
+    #[test]
+    fn normalizes_accepted_urls() {
+        let url = policy().check("HTTPS://Example.COM/a b").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/a%20b");
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        let policy = policy();
+        assert!(rejected(&policy, "https://exa\tmple.com/"));
+        assert!(rejected(&policy, "https://example.com/\n"));
+        assert!(rejected(&policy, "https://example.com/\u{7f}"));
+    }
+
+    #[test]
+    fn rejects_other_schemes_and_credentials() {
+        let policy = policy();
+        assert!(rejected(&policy, "javascript:alert(1)"));
+        assert!(rejected(&policy, "ftp://example.com/"));
+        assert!(rejected(&policy, "https://user@example.com/"));
+        assert!(rejected(&policy, "https://:secret@example.com/"));
+        assert!(rejected(&policy, "not a url"));
+    }
+
+    #[test]
+    fn rejects_overlong_urls() {
+        let url = format!("https://example.com/{}", "a".repeat(MAX_URL_LEN));
+        assert!(rejected(&policy(), &url));
+    }
+
+    #[test]
+    fn self_host_matches_its_port_only() {
+        let policy = policy();
+        assert!(rejected(&policy, "http://sho.rt:8080/abc"));
+        assert!(rejected(&policy, "http://SHO.RT.:8080/abc"));
+        assert!(policy.check("http://sho.rt/abc").is_ok());
+        assert!(policy.check("https://sho.rt:8443/abc").is_ok());
+
+        // without a port every port of the host loops back
+        let policy = UrlPolicy::new(Vec::new(), Vec::new()).with_self_host("sho.rt");
+        assert!(rejected(&policy, "http://sho.rt/abc"));
+        assert!(rejected(&policy, "https://sho.rt:8443/abc"));
+    }
+
+    #[test]
+    fn self_host_with_default_port() {
+        let policy = UrlPolicy::new(Vec::new(), Vec::new()).with_self_host("sho.rt:80");
+        assert!(rejected(&policy, "http://sho.rt/abc"));
+        assert!(rejected(&policy, "https://sho.rt:80/abc"));
+        assert!(policy.check("https://sho.rt/abc").is_ok());
+
+        let policy = UrlPolicy::new(Vec::new(), Vec::new()).with_self_host("sho.rt:443");
+        assert!(rejected(&policy, "https://sho.rt/abc"));
+        assert!(policy.check("http://sho.rt/abc").is_ok());
+    }
+
+    #[test]
+    fn blocked_domains_match_subdomains() {
+        let policy = policy();
+        assert!(rejected(&policy, "https://evil.com/"));
+        assert!(rejected(&policy, "https://www.EVIL.com./"));
+        assert!(policy.check("https://notevil.com/").is_ok());
+        assert!(policy.check("https://evil.com.example.org/").is_ok());
+    }
+
+    #[test]
+    fn allowed_domains_match_subdomains() {
+        let policy = UrlPolicy::new(vec!["example.com".to_string()], Vec::new());
+        assert!(policy.check("https://example.com/").is_ok());
+        assert!(policy.check("https://a.b.example.com/").is_ok());
+        assert!(rejected(&policy, "https://badexample.com/"));
+        assert!(rejected(&policy, "https://example.org/"));
+    }
+}
@@ -0,0 +1,80 @@
+use axum::response::{Html, IntoResponse, Response};
+use http::header::CACHE_CONTROL;
+
+use crate::{destination, error::ShortenerError, store::UrlRecord};
+
+/// Page showing the destination of `short_url`, so visitors can check it before going there.
+/// Links rotating between several destinations list all of them with their chance.
+pub fn page(short_url: &str, record: &UrlRecord) -> Result<Response, ShortenerError> {
+    let short_url = escape(short_url);
+    let total: u64 = record
+        .destinations
+        .iter()
+        .flat_map(|destinations| destinations.iter())
+        .map(|d| u64::from(d.weight))
+        .sum();
+    let mut links = String::new();
+    for variant in 1..=destination::variants(record) {
+        let url = escape(&destination::render_variant(record, variant)?);
+        let chance = match record
+            .destinations
+            .as_ref()
+            .and_then(|destinations| destinations.get(variant - 1))
+        {
+            Some(destination) if total > 0 => {
+                format!(" ({}%)", u64::from(destination.weight) * 100 / total)
+            }
+            _ => String::new(),
+        };
+        links.push_str(&format!(
+            r#"<li><a class="url" href="{url}" rel="noopener noreferrer nofollow">{url}</a>{chance}</li>"#
+        ));
+        links.push('\n');
+    }
+    let goes_to = if record.destinations.is_some() {
+        "goes to one of"
+    } else {
+        "goes to"
+    };
+    let expires = record
+        .expires_at
+        .map(|at| format!("<p>Expires {}.</p>", at.format("%Y-%m-%d %H:%M UTC")))
+        .unwrap_or_default();
+    let page = format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Link preview</title>
+<style>body {{ font-family: sans-serif; max-width: 36rem; margin: 4rem auto; }} .url {{ word-break: break-all; }}</style>
+</head>
+<body>
+<p><span class="url">{short_url}</span> {goes_to}</p>
+<ul>
+{links}</ul>
+{expires}
+</body>
+</html>
+"#
+    );
+    // the destination can be edited, so don't keep it around
+    Ok(([(CACHE_CONTROL, "no-store")], Html(page)).into_response())
+}
+
+/// Escape text for html content and quoted attribute values.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
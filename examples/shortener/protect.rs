@@ -0,0 +1,150 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::response::{Html, IntoResponse, Response};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::{header::CACHE_CONTROL, StatusCode};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::task;
+use utoipa::IntoParams;
+
+use crate::{error::ShortenerError, store::LinkKey};
+
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `?exp=<unix seconds>&sig=<mac>` of a signed short url.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct SignatureQuery {
+    /// expiry of a signed url, in unix seconds
+    exp: Option<i64>,
+    /// signature of a signed url
+    sig: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockReq {
+    pub password: String,
+}
+
+/// Signs `(link, expiry)` pairs with HMAC-SHA256.
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    /// Query pairs that make `key` redirect until `expires_at`.
+    pub fn sign(&self, key: &LinkKey, expires_at: DateTime<Utc>) -> [(&'static str, String); 2] {
+        let exp = expires_at.timestamp();
+        let sig = URL_SAFE_NO_PAD.encode(self.mac(key, exp).finalize().into_bytes());
+        [("exp", exp.to_string()), ("sig", sig)]
+    }
+
+    /// Check the signature `query` carries for `key`, returning until when it is valid.
+    pub fn verify(
+        &self,
+        key: &LinkKey,
+        query: &SignatureQuery,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, ShortenerError> {
+        let (Some(exp), Some(sig)) = (query.exp, &query.sig) else {
+            return Err(ShortenerError::InvalidSignature);
+        };
+        let sig = URL_SAFE_NO_PAD
+            .decode(sig)
+            .map_err(|_| ShortenerError::InvalidSignature)?;
+        // verify_slice compares in constant time
+        self.mac(key, exp)
+            .verify_slice(&sig)
+            .map_err(|_| ShortenerError::InvalidSignature)?;
+        match DateTime::from_timestamp(exp, 0) {
+            Some(expires_at) if expires_at > now => Ok(expires_at),
+            _ => Err(ShortenerError::InvalidSignature),
+        }
+    }
+
+    fn mac(&self, key: &LinkKey, exp: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac takes keys of any size");
+        // `id:exp` on the default domain, so urls signed before domains existed keep working
+        mac.update(format!("{key}:{exp}").as_bytes());
+        mac
+    }
+}
+
+impl fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UrlSigner").finish_non_exhaustive()
+    }
+}
+
+/// Argon2 hash of `password` in phc format. Hashing is slow on purpose, so it runs
+/// on the blocking pool.
+pub async fn hash_password(password: &str) -> Result<String, ShortenerError> {
+    let password = password.to_string();
+    task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| ShortenerError::Internal(anyhow!("hash password: {e}")))
+    })
+    .await
+    .map_err(|e| ShortenerError::Internal(e.into()))?
+}
+
+pub async fn verify_password(password: &str, hash: String) -> Result<bool, ShortenerError> {
+    let password = password.to_string();
+    task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash)
+            .map_err(|e| ShortenerError::Internal(anyhow!("stored password hash: {e}")))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await
+    .map_err(|e| ShortenerError::Internal(e.into()))?
+}
+
+/// Page asking for the password of a protected link. It posts back to the same url,
+/// so the signature query of signed links comes along.
+pub fn unlock_form(status: StatusCode, error: Option<&str>) -> Response {
+    let error = error
+        .map(|e| format!("<p class=\"error\">{e}</p>"))
+        .unwrap_or_default();
+    let page = format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Protected link</title>
+<style>body {{ font-family: sans-serif; max-width: 24rem; margin: 4rem auto; }} .error {{ color: #b00; }}</style>
+</head>
+<body>
+<form method="post">
+<p>This link is password protected.</p>
+{error}
+<input type="password" name="password" aria-label="Password" autofocus required>
+<button type="submit">Continue</button>
+</form>
+</body>
+</html>
+"#
+    );
+    (status, [(CACHE_CONTROL, "no-store")], Html(page)).into_response()
+}
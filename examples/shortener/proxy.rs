@@ -0,0 +1,123 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+};
+use http::{header::FORWARDED, request::Parts, HeaderMap};
+use ipnet::IpNet;
+
+use crate::AppState;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Reverse proxies whose `Forwarded` / `X-Forwarded-*` headers are believed. Anyone else
+/// could send them to pose as another client.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+/// Ip of the client a request came from, see `TrustedProxies::client_ip`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl TrustedProxies {
+    /// `proxies` are networks such as `10.0.0.0/8` or single addresses.
+    pub fn new(proxies: &[String]) -> Result<Self> {
+        let nets = proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("invalid trusted proxy: {proxy}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { nets })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client behind `peer`. Each trusted proxy appends the address it got the request
+    /// from, so the forwarded chain is walked from the end while the hops are trusted.
+    /// `Forwarded` wins over `X-Forwarded-For` when a request has both.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let chain = match forwarded_for(headers, FORWARDED.as_str(), forwarded_node) {
+            chain if !chain.is_empty() => chain,
+            _ => forwarded_for(headers, X_FORWARDED_FOR, |hop| hop.parse().ok()),
+        };
+        for hop in chain.into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            // obfuscated or unknown hops end the chain at the proxy that added them
+            match hop {
+                Some(ip) => client = ip.to_canonical(),
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The hops of every `name` header in order, `None` for the ones that aren't an ip.
+fn forwarded_for(
+    headers: &HeaderMap,
+    name: &str,
+    parse: impl Fn(&str) -> Option<IpAddr>,
+) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(name)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .map(parse)
+        .collect()
+}
+
+/// The `for=` node of a `Forwarded` element, e.g. `for=192.0.2.60;proto=http` or
+/// `for="[2001:db8::17]:4711"`.
+fn forwarded_node(element: &str) -> Option<IpAddr> {
+    let node = element.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let node = node.trim_matches('"');
+    match node.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?.parse().ok(),
+        None => node
+            .parse()
+            .ok()
+            .or_else(|| node.rsplit_once(':')?.0.parse().ok()),
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // served with connect info, only tests without it would see the fallback
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(IpAddr::from([0, 0, 0, 0]), |ConnectInfo(addr)| addr.ip());
+        Ok(Self(state.proxies.client_ip(peer, &parts.headers)))
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use http::{
+    header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE},
+    HeaderMap,
+};
+use image::{ImageFormat, Luma};
+use qrcode::{render::svg, EcLevel, QrCode};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{domain::RequestDomain, error::ShortenerError, protect::SignatureQuery, AppState};
+
+const DEFAULT_SIZE: u32 = 256;
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+const SVG: &str = "image/svg+xml";
+const PNG: &str = "image/png";
+const MAX_AGE: TimeDelta = TimeDelta::days(1);
+
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum QrFormat {
+    Svg,
+    Png,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+enum Ecc {
+    #[serde(alias = "l")]
+    L,
+    #[default]
+    #[serde(alias = "m")]
+    M,
+    #[serde(alias = "q")]
+    Q,
+    #[serde(alias = "h")]
+    H,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct QrQuery {
+    /// overrides the `Accept` header
+    #[param(inline)]
+    format: Option<QrFormat>,
+    /// minimum width and height in pixels, the code is never scaled below its module grid
+    size: Option<u32>,
+    /// error correction level, higher levels survive more damage (or a logo on top)
+    #[serde(default)]
+    #[param(inline)]
+    ecc: Ecc,
+}
+
+/// `GET /:id/qr`, the QR code of the short url. Svg unless png is asked for. Signed
+/// links need the `exp` and `sig` of their signed url, which the code then holds.
+#[utoipa::path(
+    get,
+    path = "/{id}/qr",
+    tag = "links",
+    params(("id" = String, Path, description = "id of the link"), QrQuery, SignatureQuery),
+    responses(
+        (status = 200, description = "the QR code", body = [u8],
+            content_type = ["image/svg+xml", "image/png"]),
+        (status = 403, description = "signed link without a valid signature", body = ErrorBody),
+        (status = 404, description = "unknown or deleted link", body = ErrorBody),
+        (status = 410, description = "expired or out of clicks", body = ErrorBody),
+    ),
+)]
+pub async fn qr_code(
+    State(state): State<AppState>,
+    RequestDomain(domain): RequestDomain,
+    Path(id): Path<String>,
+    Query(query): Query<QrQuery>,
+    Query(signature): Query<SignatureQuery>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let key = domain.key(&id);
+    let record = state.store.get(&key).await?;
+    if record.deleted_at.is_some() {
+        return Err(ShortenerError::NotFound);
+    }
+    let now = Utc::now();
+    if !record.is_alive(now) {
+        return Err(ShortenerError::Expired);
+    }
+    // the bare short url of a signed link only answers 403
+    let (url, signature_expires_at) = if record.signed {
+        let expires_at = state.signer.verify(&key, &signature, now)?;
+        (
+            state.signed_url(&domain, &id, expires_at)?,
+            Some(expires_at),
+        )
+    } else {
+        (domain.short_url(&id)?.into(), None)
+    };
+
+    let format = query.format.unwrap_or_else(|| preferred_format(&headers));
+    let size = query.size.unwrap_or(DEFAULT_SIZE).clamp(MIN_SIZE, MAX_SIZE);
+    let code = QrCode::with_error_correction_level(url, query.ecc.into())
+        .map_err(|e| ShortenerError::Internal(anyhow!("encode qr code of {id}: {e}")))?;
+    let (content_type, body) = match format {
+        QrFormat::Svg => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            (SVG, image.into_bytes())
+        }
+        QrFormat::Png => {
+            let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+            let mut buf = Cursor::new(Vec::new());
+            image
+                .write_to(&mut buf, ImageFormat::Png)
+                .map_err(|e| ShortenerError::Internal(e.into()))?;
+            (PNG, buf.into_inner())
+        }
+    };
+    // the code holds the short url, not the destination, so edits don't change it
+    let expires_at = record
+        .expires_at
+        .into_iter()
+        .chain(signature_expires_at)
+        .min();
+    let headers = [
+        (CONTENT_TYPE, content_type.to_string()),
+        (CACHE_CONTROL, cache_control(expires_at, now)),
+    ];
+    Ok((headers, body))
+}
+
+/// Private so shared caches don't keep serving codes of deleted links, and never cached
+/// past `expires_at`, when the code stops working.
+fn cache_control(expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+    let max_age = expires_at.map_or(MAX_AGE, |expires_at| (expires_at - now).min(MAX_AGE));
+    format!("private, max-age={}", max_age.num_seconds().max(0))
+}
+
+/// Png when the client accepts png but not svg, svg otherwise since it prints at any size.
+fn preferred_format(headers: &HeaderMap) -> QrFormat {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if accept.contains(PNG) && !accept.contains(SVG) {
+        QrFormat::Png
+    } else {
+        QrFormat::Svg
+    }
+}
+
+impl From<Ecc> for EcLevel {
+    fn from(ecc: Ecc) -> Self {
+        match ecc {
+            Ecc::L => Self::L,
+            Ecc::M => Self::M,
+            Ecc::Q => Self::Q,
+            Ecc::H => Self::H,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_control_stops_at_the_expiry() {
+        let now = Utc::now();
+        assert_eq!(cache_control(None, now), "private, max-age=86400");
+        let soon = now + TimeDelta::seconds(90);
+        assert_eq!(cache_control(Some(soon), now), "private, max-age=90");
+        let later = now + TimeDelta::days(7);
+        assert_eq!(cache_control(Some(later), now), "private, max-age=86400");
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use dashmap::DashMap;
+use futures::future::{self, Either, Ready};
+use http::header::AUTHORIZATION;
+use tokio::time::{self, Instant};
+use tower::{Layer, Service};
+
+use crate::{
+    auth::{self, Caller, Credentials},
+    config::RateLimitConfig,
+    error::ShortenerError,
+    proxy::TrustedProxies,
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket rate limit of one route. Requests with an api key are counted per owner,
+/// the others per client ip (per /64 for ipv6), as forwarded by trusted proxies.
+/// The admin token is not limited.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+#[derive(Clone)]
+pub struct RateLimited<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+struct Limiter {
+    /// tokens added per second
+    rate: f64,
+    burst: f64,
+    credentials: Arc<Credentials>,
+    proxies: Arc<TrustedProxies>,
+    buckets: DashMap<Client, Bucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Owner(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimitLayer {
+    /// Also spawns the task forgetting idle clients, it ends with the last clone of the layer.
+    pub fn new(
+        config: &RateLimitConfig,
+        credentials: Arc<Credentials>,
+        proxies: Arc<TrustedProxies>,
+    ) -> Self {
+        let limiter = Arc::new(Limiter {
+            rate: config.per_minute as f64 / 60.0,
+            burst: config.burst as f64,
+            credentials,
+            proxies,
+            buckets: DashMap::new(),
+        });
+        tokio::spawn(prune(Arc::downgrade(&limiter)));
+        Self { limiter }
+    }
+}
+
+/// `route` behind `limit` when one is configured.
+pub fn limited<S>(route: MethodRouter<S>, limit: Option<&RateLimitLayer>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match limit {
+        Some(limit) => route.layer(limit.clone()),
+        None => route,
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimited<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimited {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request> for RateLimited<S>
+where
+    S: Service<Request, Response = Response>,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let retry_after = self
+            .limiter
+            .client(&req)
+            .and_then(|client| self.limiter.acquire(client, Instant::now()).err());
+        match retry_after {
+            Some(secs) => {
+                let res = ShortenerError::RateLimited(secs).into_response();
+                Either::Left(future::ready(Ok(res)))
+            }
+            None => Either::Right(self.inner.call(req)),
+        }
+    }
+}
+
+impl Limiter {
+    fn client(&self, req: &Request) -> Option<Client> {
+        let caller = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(auth::bearer_token)
+            .and_then(|token| self.credentials.caller(token));
+        match caller {
+            Some(Caller::Admin) => None,
+            Some(Caller::Key(key)) => Some(Client::Owner(key.owner.clone())),
+            // invalid tokens are rejected by the handler, they still count against the ip
+            _ => req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| {
+                    let ip = self.proxies.client_ip(addr.ip(), req.headers());
+                    Client::Ip(network(ip))
+                }),
+        }
+    }
+
+    /// Take a token from the bucket of `client`, or the seconds until there is one.
+    fn acquire(&self, client: Client, now: Instant) -> Result<(), u64> {
+        let mut bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// A full bucket is the same as no bucket, so drop those to keep the map small.
+async fn prune(limiter: Weak<Limiter>) {
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(limiter) = limiter.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        limiter
+            .buckets
+            .retain(|_, bucket| limiter.refill(bucket, now) < limiter.burst);
+    }
+}
+
+/// Ipv6 clients usually get a whole /64, so limiting single addresses would be no limit.
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = u128::from(v6) & !(u128::MAX >> 64);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_minute: u32, burst: u32) -> Limiter {
+        Limiter {
+            rate: per_minute as f64 / 60.0,
+            burst: burst as f64,
+            credentials: Default::default(),
+            proxies: Default::default(),
+            buckets: DashMap::new(),
+        }
+    }
+
+    fn ip(ip: &str) -> Client {
+        Client::Ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn bursts_then_waits_for_refill() {
+        let limiter = limiter(60, 2);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Err(1));
+        // half a token isn't enough
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Err(1));
+        let later = now + Duration::from_millis(1500);
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Err(1));
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let limiter = limiter(60, 2);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Ok(()));
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Err(1));
+    }
+
+    #[test]
+    fn retry_after_follows_the_rate() {
+        let limiter = limiter(6, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Err(10));
+        let later = now + Duration::from_secs(4);
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), later), Err(6));
+    }
+
+    #[test]
+    fn clients_have_their_own_buckets() {
+        let limiter = limiter(60, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(ip("192.0.2.1"), now), Ok(()));
+        assert_eq!(limiter.acquire(ip("192.0.2.2"), now), Ok(()));
+        let owner = Client::Owner("growth".to_string());
+        assert_eq!(limiter.acquire(owner.clone(), now), Ok(()));
+        assert_eq!(limiter.acquire(owner, now), Err(1));
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_per_64() {
+        let ip = |ip: &str| network(ip.parse().unwrap());
+        assert_eq!(ip("2001:db8:1:2:3:4:5:6"), ip("2001:db8:1:2::"));
+        assert_ne!(ip("2001:db8:1:2::"), ip("2001:db8:1:3::"));
+        assert_eq!(ip("::ffff:192.0.2.1"), ip("192.0.2.1"));
+        assert_eq!(ip("192.0.2.1").to_string(), "192.0.2.1");
+    }
+}
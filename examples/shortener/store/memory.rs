@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use super::{
+    Bucket, ClickBucket, ClickEvent, LinkKey, ListFilter, Migrate, NewUrl, StoreError, UrlRecord,
+    UrlStore,
+};
+
+/// Non persistent store, handy for local runs and CI where no database is available.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    urls: DashMap<LinkKey, UrlRecord>,
+    // link -> click time of every recorded click
+    clicks: DashMap<LinkKey, Vec<DateTime<Utc>>>,
+}
+
+#[async_trait]
+impl UrlStore for MemoryStore {
+    async fn migrate(&self, _to: Migrate) -> Result<()> {
+        // nothing is kept across restarts, so there is no schema to evolve
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn close(&self) {}
+
+    async fn shorten(&self, new: &NewUrl) -> Result<(), StoreError> {
+        match self.urls.entry(LinkKey::new(&new.domain, &new.id)) {
+            Entry::Occupied(_) => Err(StoreError::Conflict(new.id.clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(new.clone().into());
+                Ok(())
+            }
+        }
+    }
+
+    async fn insert_many(&self, records: &[UrlRecord]) -> Result<Vec<bool>, StoreError> {
+        let inserted = records
+            .iter()
+            .map(|record| match self.urls.entry(record.key()) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(record.clone());
+                    true
+                }
+            })
+            .collect();
+        Ok(inserted)
+    }
+
+    async fn resolve(&self, key: &LinkKey, unlocked: bool) -> Result<UrlRecord, StoreError> {
+        let mut record = self
+            .urls
+            .get_mut(key)
+            .filter(|record| record.deleted_at.is_none())
+            .ok_or(StoreError::NotFound)?;
+        let now = Utc::now();
+        if !record.is_alive(now) {
+            return Err(StoreError::Expired);
+        }
+        if let Some(lock) = record.lock().filter(|_| !unlocked) {
+            return Err(StoreError::Locked(lock));
+        }
+        record.clicks += 1;
+        record.last_clicked_at = Some(now);
+        Ok(record.clone())
+    }
+
+    async fn get(&self, key: &LinkKey) -> Result<UrlRecord, StoreError> {
+        self.urls
+            .get(key)
+            .map(|record| record.clone())
+            .ok_or(StoreError::NotFound)
+    }
+
+    async fn delete(&self, key: &LinkKey) -> Result<(), StoreError> {
+        self.urls.remove(key).ok_or(StoreError::NotFound)?;
+        self.clicks.remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, filter: &ListFilter) -> Result<Vec<UrlRecord>, StoreError> {
+        let contains = filter.contains.as_ref().map(|c| c.to_lowercase());
+        let mut ret: Vec<_> = self
+            .urls
+            .iter()
+            .filter(|r| {
+                filter
+                    .after
+                    .as_ref()
+                    .is_none_or(|(at, id)| (r.created_at, &r.id) < (*at, id))
+                    && filter.created_after.is_none_or(|at| r.created_at >= at)
+                    && filter.created_before.is_none_or(|at| r.created_at < at)
+                    && contains
+                        .as_ref()
+                        .is_none_or(|c| r.url.to_lowercase().contains(c))
+                    && (filter.include_deleted || r.deleted_at.is_none())
+                    && filter
+                        .owner
+                        .as_ref()
+                        .is_none_or(|owner| r.owner.as_ref() == Some(owner))
+                    && filter
+                        .domain
+                        .as_ref()
+                        .is_none_or(|domain| &r.domain == domain)
+            })
+            .map(|entry| entry.clone())
+            .collect();
+        ret.sort_by(|a, b| (b.created_at, &b.id).cmp(&(a.created_at, &a.id)));
+        ret.truncate(filter.limit);
+        Ok(ret)
+    }
+
+    async fn count_owned(&self, owner: &str) -> Result<u64, StoreError> {
+        let count = self
+            .urls
+            .iter()
+            .filter(|r| r.owner.as_deref() == Some(owner) && r.deleted_at.is_none())
+            .count();
+        Ok(count as u64)
+    }
+
+    async fn update_url(&self, key: &LinkKey, url: &str) -> Result<UrlRecord, StoreError> {
+        let mut record = self.urls.get_mut(key).ok_or(StoreError::NotFound)?;
+        record.url = url.to_string();
+        record.destinations = None;
+        Ok(record.clone())
+    }
+
+    async fn set_deleted(&self, key: &LinkKey, deleted: bool) -> Result<UrlRecord, StoreError> {
+        let mut record = self.urls.get_mut(key).ok_or(StoreError::NotFound)?;
+        record.deleted_at = deleted.then(Utc::now);
+        Ok(record.clone())
+    }
+
+    async fn purge_expired(&self, before: DateTime<Utc>) -> Result<u64, StoreError> {
+        let mut purged = 0;
+        self.urls.retain(|_, record| {
+            let expired = record
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= before);
+            let exhausted = record.max_clicks.is_some_and(|max| record.clicks >= max)
+                && record.last_clicked_at.unwrap_or(record.created_at) <= before;
+            if expired || exhausted {
+                purged += 1;
+            }
+            !(expired || exhausted)
+        });
+        self.clicks.retain(|key, _| self.urls.contains_key(key));
+        Ok(purged)
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), StoreError> {
+        for click in clicks {
+            self.clicks
+                .entry(LinkKey::new(&click.domain, &click.url_id))
+                .or_default()
+                .push(click.clicked_at);
+        }
+        Ok(())
+    }
+
+    async fn add_clicks(&self, counts: &[(LinkKey, i64)]) -> Result<(), StoreError> {
+        let now = Utc::now();
+        for (key, n) in counts {
+            if let Some(mut record) = self.urls.get_mut(key) {
+                record.clicks += n;
+                record.last_clicked_at = Some(now);
+            }
+        }
+        Ok(())
+    }
+
+    async fn click_stats(
+        &self,
+        key: &LinkKey,
+        bucket: Bucket,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<ClickBucket>, StoreError> {
+        let mut buckets = BTreeMap::new();
+        if let Some(clicks) = self.clicks.get(key) {
+            for at in clicks.iter().filter(|at| **at >= since) {
+                *buckets.entry(bucket.trunc(*at)).or_insert(0) += 1;
+            }
+        }
+        Ok(buckets
+            .into_iter()
+            .map(|(start, clicks)| ClickBucket { start, clicks })
+            .collect())
+    }
+}
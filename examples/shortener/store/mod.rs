@@ -0,0 +1,409 @@
+mod memory;
+mod postgres;
+mod sqlite;
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use thiserror::Error;
+use utoipa::{
+    openapi::{ObjectBuilder, RefOr, Schema, SchemaType},
+    ToSchema,
+};
+
+pub use memory::MemoryStore;
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct UrlRecord {
+    pub id: String,
+    pub url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub clicks: i64,
+    pub created_at: DateTime<Utc>,
+    /// when the last counted redirect happened, dead links are purged a while after it
+    #[serde(default)]
+    pub last_clicked_at: Option<DateTime<Utc>>,
+    /// soft deleted links don't redirect but can be restored
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// argon2 hash in phc format, the link asks for the password before redirecting.
+    /// Only `GET /export` hands it out, see `bulk::ExportRecord`.
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+    /// the link only redirects with a valid `exp` / `sig` query
+    #[serde(default)]
+    pub signed: bool,
+    /// team of the api key that created the link, `None` for the admin and open instances
+    pub owner: Option<String>,
+    #[serde(default)]
+    #[sqlx(try_from = "i32")]
+    pub redirect_status: RedirectStatus,
+    /// weighted destinations rotated per redirect, `url` is the first of them
+    #[schema(value_type = Option<Vec<Destination>>)]
+    pub destinations: Option<Json<Vec<Destination>>>,
+    /// appended to the destination on redirect, see `destination::render`
+    #[schema(value_type = Option<BTreeMap<String, String>>)]
+    pub query_params: Option<Json<QueryParams>>,
+    /// host the link is served on, empty for the default domain of `base_url`
+    #[serde(default)]
+    pub domain: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewUrl {
+    pub id: String,
+    pub url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub password_hash: Option<String>,
+    pub signed: bool,
+    pub owner: Option<String>,
+    pub redirect_status: RedirectStatus,
+    pub destinations: Option<Json<Vec<Destination>>>,
+    pub query_params: Option<Json<QueryParams>>,
+    pub domain: String,
+}
+
+/// A link is addressed by its id within a domain, every domain has its own ids.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LinkKey {
+    /// empty for the default domain
+    pub domain: String,
+    pub id: String,
+}
+
+/// One destination of a rotating link, picked with a chance of `weight` / total weight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Destination {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Query parameter name -> value template.
+pub type QueryParams = BTreeMap<String, String>;
+
+/// Status code a link redirects with. Browsers cache permanent redirects, so later
+/// clicks on them never reach the service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "i32", into = "i32")]
+pub enum RedirectStatus {
+    /// 301
+    MovedPermanently,
+    /// 302
+    #[default]
+    Found,
+    /// 307, keeps the method and body
+    TemporaryRedirect,
+    /// 308, keeps the method and body
+    PermanentRedirect,
+}
+
+#[derive(Debug, Error)]
+#[error("unsupported redirect status {0}, expected 301, 302, 307 or 308")]
+pub struct InvalidRedirectStatus(i32);
+
+/// Filter of `UrlStore::list`, results are ordered newest first.
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter {
+    /// only records strictly after this `(created_at, id)` position
+    pub after: Option<(DateTime<Utc>, String)>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// case insensitive substring of the destination url
+    pub contains: Option<String>,
+    pub include_deleted: bool,
+    /// only records of this owner
+    pub owner: Option<String>,
+    /// only records of this domain, all domains when `None`
+    pub domain: Option<String>,
+    pub limit: usize,
+}
+
+/// One redirect, recorded by the analytics writer.
+#[derive(Debug, Clone)]
+pub struct ClickEvent {
+    pub domain: String,
+    pub url_id: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bucket {
+    Day,
+    Hour,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct ClickBucket {
+    pub start: DateTime<Utc>,
+    pub clicks: i64,
+}
+
+/// What a protected link needs before `UrlStore::resolve` lets it through.
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub password_hash: Option<String>,
+    pub signed: bool,
+}
+
+/// Direction of `UrlStore::migrate`.
+#[derive(Debug, Clone, Copy)]
+pub enum Migrate {
+    /// apply every pending migration
+    Up,
+    /// revert the applied migrations newer than this version, 0 reverts all of them
+    Down(i64),
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("url not found")]
+    NotFound,
+
+    #[error("id already taken: {0}")]
+    Conflict(String),
+
+    #[error("url expired or reached its click limit")]
+    Expired,
+
+    #[error("url is protected")]
+    Locked(Lock),
+
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Storage backend of the shortener. `AppState` only talks to this trait so the
+/// server can run against postgres, sqlite or plain memory.
+#[async_trait]
+pub trait UrlStore: fmt::Debug + Send + Sync {
+    /// Bring the schema to `to` with the migrations embedded in the binary.
+    async fn migrate(&self, to: Migrate) -> Result<()>;
+
+    /// Check the database answers, for the readiness probe.
+    async fn ping(&self) -> Result<(), StoreError>;
+
+    /// Wait for pending writes and close the connections, on shutdown.
+    async fn close(&self);
+
+    /// Store a new url, fails with `StoreError::Conflict` if the id is taken.
+    async fn shorten(&self, new: &NewUrl) -> Result<(), StoreError>;
+
+    /// Insert all `records` in one transaction, ids already taken are skipped.
+    /// Returns for every record whether it was inserted.
+    async fn insert_many(&self, records: &[UrlRecord]) -> Result<Vec<bool>, StoreError>;
+
+    /// Return the record of `key` and count the click. Links past `expires_at` or
+    /// `max_clicks` fail with `StoreError::Expired`, soft deleted ones with `NotFound`.
+    /// Password protected or signed links fail with `StoreError::Locked` without
+    /// counting, unless the caller already checked them and passes `unlocked`.
+    async fn resolve(&self, key: &LinkKey, unlocked: bool) -> Result<UrlRecord, StoreError>;
+
+    /// Return the record of `key` without counting a click, soft deleted ones included.
+    async fn get(&self, key: &LinkKey) -> Result<UrlRecord, StoreError>;
+
+    /// Delete the link and its clicks for good.
+    async fn delete(&self, key: &LinkKey) -> Result<(), StoreError>;
+
+    async fn list(&self, filter: &ListFilter) -> Result<Vec<UrlRecord>, StoreError>;
+
+    /// Number of links of `owner` that aren't soft deleted.
+    async fn count_owned(&self, owner: &str) -> Result<u64, StoreError>;
+
+    /// Point `key` at a new destination, this ends the rotation of a link with several.
+    async fn update_url(&self, key: &LinkKey, url: &str) -> Result<UrlRecord, StoreError>;
+
+    /// Soft delete (`deleted = true`) or restore `key`.
+    async fn set_deleted(&self, key: &LinkKey, deleted: bool) -> Result<UrlRecord, StoreError>;
+
+    /// Delete the urls that expired or were last clicked before `before` and can't redirect
+    /// anymore, together with their clicks. Returns how many urls were removed.
+    async fn purge_expired(&self, before: DateTime<Utc>) -> Result<u64, StoreError>;
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), StoreError>;
+
+    /// Add `(key, clicks)` to the click counters of redirects served without `resolve`.
+    async fn add_clicks(&self, counts: &[(LinkKey, i64)]) -> Result<(), StoreError>;
+
+    /// Click counts of `key` since `since`, grouped per `bucket` and ordered by time.
+    async fn click_stats(
+        &self,
+        key: &LinkKey,
+        bucket: Bucket,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<ClickBucket>, StoreError>;
+}
+
+impl Bucket {
+    pub fn duration(&self) -> TimeDelta {
+        match self {
+            Self::Day => TimeDelta::days(1),
+            Self::Hour => TimeDelta::hours(1),
+        }
+    }
+
+    pub fn trunc(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        at.duration_trunc(self.duration()).unwrap_or(at)
+    }
+}
+
+impl From<NewUrl> for UrlRecord {
+    fn from(new: NewUrl) -> Self {
+        Self {
+            id: new.id,
+            url: new.url,
+            expires_at: new.expires_at,
+            max_clicks: new.max_clicks,
+            clicks: 0,
+            created_at: new.created_at,
+            last_clicked_at: None,
+            deleted_at: None,
+            password_hash: new.password_hash,
+            signed: new.signed,
+            owner: new.owner,
+            redirect_status: new.redirect_status,
+            destinations: new.destinations,
+            query_params: new.query_params,
+            domain: new.domain,
+        }
+    }
+}
+
+impl LinkKey {
+    pub fn new(domain: &str, id: &str) -> Self {
+        Self {
+            domain: domain.to_string(),
+            id: id.to_string(),
+        }
+    }
+}
+
+/// `id` on the default domain, `domain/id` elsewhere. Ids can't contain a slash.
+impl fmt::Display for LinkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.domain.is_empty() {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{}/{}", self.domain, self.id)
+        }
+    }
+}
+
+impl RedirectStatus {
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            Self::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            Self::Found => StatusCode::FOUND,
+            Self::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            Self::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    pub fn is_permanent(self) -> bool {
+        matches!(self, Self::MovedPermanently | Self::PermanentRedirect)
+    }
+}
+
+impl TryFrom<i32> for RedirectStatus {
+    type Error = InvalidRedirectStatus;
+
+    fn try_from(status: i32) -> Result<Self, Self::Error> {
+        match status {
+            301 => Ok(Self::MovedPermanently),
+            302 => Ok(Self::Found),
+            307 => Ok(Self::TemporaryRedirect),
+            308 => Ok(Self::PermanentRedirect),
+            _ => Err(InvalidRedirectStatus(status)),
+        }
+    }
+}
+
+// serde writes the status code, which the derive can't know
+impl<'s> ToSchema<'s> for RedirectStatus {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let schema = ObjectBuilder::new()
+            .schema_type(SchemaType::Integer)
+            .enum_values(Some([301, 302, 307, 308]))
+            .default(Some(302.into()))
+            .description(Some("status code the link redirects with"));
+        ("RedirectStatus", schema.into())
+    }
+}
+
+impl From<RedirectStatus> for i32 {
+    fn from(status: RedirectStatus) -> Self {
+        status.status_code().as_u16().into()
+    }
+}
+
+impl UrlRecord {
+    pub fn key(&self) -> LinkKey {
+        LinkKey::new(&self.domain, &self.id)
+    }
+
+    /// No expiry, click limit, lock or rewriting, so one link can serve everyone
+    /// shortening the url.
+    pub fn is_plain(&self) -> bool {
+        self.expires_at.is_none()
+            && self.max_clicks.is_none()
+            && self.lock().is_none()
+            && self.destinations.is_none()
+            && self.query_params.is_none()
+    }
+
+    pub fn lock(&self) -> Option<Lock> {
+        (self.password_hash.is_some() || self.signed).then(|| Lock {
+            password_hash: self.password_hash.clone(),
+            signed: self.signed,
+        })
+    }
+
+    pub fn is_alive(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && self.max_clicks.is_none_or(|max| self.clicks < max)
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Why `resolve` refused `record`, the live, not deleted row of the id if there is one.
+fn resolve_error(record: Option<UrlRecord>, now: DateTime<Utc>) -> StoreError {
+    match record {
+        None => StoreError::NotFound,
+        Some(record) if !record.is_alive(now) => StoreError::Expired,
+        // no lock means the last click was taken concurrently
+        Some(record) => record
+            .lock()
+            .map_or(StoreError::Expired, StoreError::Locked),
+    }
+}
+
+/// Open the backend matching the scheme of `url`:
+/// `postgres://...`, `sqlite://path.db` / `sqlite::memory:` or `memory://`.
+/// Pending migrations are applied first when `auto_migrate` is set.
+pub async fn open(url: &str, auto_migrate: bool) -> Result<Arc<dyn UrlStore>> {
+    let store: Arc<dyn UrlStore> = match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("postgres" | "postgresql") => Arc::new(PgStore::try_new(url).await?),
+        Some("sqlite") => Arc::new(SqliteStore::try_new(url).await?),
+        Some("memory") => Arc::new(MemoryStore::default()),
+        _ => bail!("unsupported database url: {url}"),
+    };
+    if auto_migrate {
+        store.migrate(Migrate::Up).await?;
+    }
+    Ok(store)
+}
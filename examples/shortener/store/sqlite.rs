@@ -0,0 +1,342 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    QueryBuilder, SqlitePool,
+};
+
+use super::{
+    resolve_error, Bucket, ClickBucket, ClickEvent, LinkKey, ListFilter, Migrate, NewUrl,
+    StoreError, UrlRecord, UrlStore,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/sqlite");
+
+/// Embedded store, `sqlite://shortener.db` or `sqlite::memory:`.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    db: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn try_new(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let mut pool = SqlitePoolOptions::new();
+        if url.contains(":memory:") {
+            // every connection of an in-memory database is a separate database,
+            // so keep exactly one connection alive for the whole process
+            pool = pool
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        let pool = pool.connect_with(options).await?;
+        Ok(Self { db: pool })
+    }
+}
+
+#[async_trait]
+impl UrlStore for SqliteStore {
+    async fn migrate(&self, to: Migrate) -> Result<()> {
+        match to {
+            Migrate::Up => MIGRATOR.run(&self.db).await?,
+            Migrate::Down(target) => MIGRATOR.undo(&self.db, target).await?,
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StoreError> {
+        sqlx::query("SELECT 1").execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.db.close().await;
+    }
+
+    async fn shorten(&self, new: &NewUrl) -> Result<(), StoreError> {
+        let ret = sqlx::query(
+            "INSERT INTO urls (id, url, expires_at, max_clicks, created_at, password_hash, signed, owner, redirect_status, destinations, query_params, domain) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT(domain, id) DO NOTHING",
+        )
+        .bind(&new.id)
+        .bind(&new.url)
+        .bind(new.expires_at)
+        .bind(new.max_clicks)
+        .bind(new.created_at)
+        .bind(&new.password_hash)
+        .bind(new.signed)
+        .bind(&new.owner)
+        .bind(i32::from(new.redirect_status))
+        .bind(&new.destinations)
+        .bind(&new.query_params)
+        .bind(&new.domain)
+        .execute(&self.db)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(StoreError::Conflict(new.id.clone()));
+        }
+        Ok(())
+    }
+
+    async fn insert_many(&self, records: &[UrlRecord]) -> Result<Vec<bool>, StoreError> {
+        let mut tx = self.db.begin().await?;
+        let mut inserted = Vec::with_capacity(records.len());
+        for record in records {
+            let ret = sqlx::query(
+                "INSERT INTO urls (id, url, expires_at, max_clicks, clicks, created_at, last_clicked_at, deleted_at, password_hash, signed, owner, redirect_status, destinations, query_params, domain) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT(domain, id) DO NOTHING",
+            )
+            .bind(&record.id)
+            .bind(&record.url)
+            .bind(record.expires_at)
+            .bind(record.max_clicks)
+            .bind(record.clicks)
+            .bind(record.created_at)
+            .bind(record.last_clicked_at)
+            .bind(record.deleted_at)
+            .bind(&record.password_hash)
+            .bind(record.signed)
+            .bind(&record.owner)
+            .bind(i32::from(record.redirect_status))
+            .bind(&record.destinations)
+            .bind(&record.query_params)
+            .bind(&record.domain)
+            .execute(&mut *tx)
+            .await?;
+            inserted.push(ret.rows_affected() > 0);
+        }
+        tx.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn resolve(&self, key: &LinkKey, unlocked: bool) -> Result<UrlRecord, StoreError> {
+        let now = Utc::now();
+        // check and count in one statement so concurrent clicks can't overshoot max_clicks
+        let record: Option<UrlRecord> = sqlx::query_as(
+            r#"
+            UPDATE urls SET clicks = clicks + 1, last_clicked_at = ?
+            WHERE domain = ? AND id = ?
+                AND deleted_at IS NULL
+                AND (expires_at IS NULL OR expires_at > ?)
+                AND (max_clicks IS NULL OR clicks < max_clicks)
+                AND ((password_hash IS NULL AND NOT signed) OR ?)
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(&key.domain)
+        .bind(&key.id)
+        .bind(now)
+        .bind(unlocked)
+        .fetch_optional(&self.db)
+        .await?;
+        if let Some(record) = record {
+            return Ok(record);
+        }
+
+        let record: Option<UrlRecord> =
+            sqlx::query_as("SELECT * FROM urls WHERE domain = ? AND id = ? AND deleted_at IS NULL")
+                .bind(&key.domain)
+                .bind(&key.id)
+                .fetch_optional(&self.db)
+                .await?;
+        Err(resolve_error(record, now))
+    }
+
+    async fn get(&self, key: &LinkKey) -> Result<UrlRecord, StoreError> {
+        let ret = sqlx::query_as("SELECT * FROM urls WHERE domain = ? AND id = ?")
+            .bind(&key.domain)
+            .bind(&key.id)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or(StoreError::NotFound)?;
+        Ok(ret)
+    }
+
+    async fn delete(&self, key: &LinkKey) -> Result<(), StoreError> {
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query("DELETE FROM urls WHERE domain = ? AND id = ?")
+            .bind(&key.domain)
+            .bind(&key.id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(StoreError::NotFound);
+        }
+        sqlx::query("DELETE FROM clicks WHERE domain = ? AND url_id = ?")
+            .bind(&key.domain)
+            .bind(&key.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list(&self, filter: &ListFilter) -> Result<Vec<UrlRecord>, StoreError> {
+        let mut query = QueryBuilder::new("SELECT * FROM urls WHERE TRUE");
+        if let Some((created_at, id)) = &filter.after {
+            query
+                .push(" AND (created_at, id) < (")
+                .push_bind(*created_at)
+                .push(", ")
+                .push_bind(id)
+                .push(")");
+        }
+        if let Some(created_after) = filter.created_after {
+            query.push(" AND created_at >= ").push_bind(created_after);
+        }
+        if let Some(created_before) = filter.created_before {
+            query.push(" AND created_at < ").push_bind(created_before);
+        }
+        if let Some(contains) = &filter.contains {
+            query
+                .push(" AND instr(lower(url), lower(")
+                .push_bind(contains)
+                .push(")) > 0");
+        }
+        if let Some(owner) = &filter.owner {
+            query.push(" AND owner = ").push_bind(owner);
+        }
+        if let Some(domain) = &filter.domain {
+            query.push(" AND domain = ").push_bind(domain);
+        }
+        if !filter.include_deleted {
+            query.push(" AND deleted_at IS NULL");
+        }
+        query
+            .push(" ORDER BY created_at DESC, id DESC LIMIT ")
+            .push_bind(filter.limit as i64);
+        let ret = query.build_query_as().fetch_all(&self.db).await?;
+        Ok(ret)
+    }
+
+    async fn count_owned(&self, owner: &str) -> Result<u64, StoreError> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM urls WHERE owner = ? AND deleted_at IS NULL")
+                .bind(owner)
+                .fetch_one(&self.db)
+                .await?;
+        Ok(count as u64)
+    }
+
+    async fn update_url(&self, key: &LinkKey, url: &str) -> Result<UrlRecord, StoreError> {
+        let ret = sqlx::query_as(
+            "UPDATE urls SET url = ?, destinations = NULL WHERE domain = ? AND id = ? RETURNING *",
+        )
+        .bind(url)
+        .bind(&key.domain)
+        .bind(&key.id)
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(StoreError::NotFound)?;
+        Ok(ret)
+    }
+
+    async fn set_deleted(&self, key: &LinkKey, deleted: bool) -> Result<UrlRecord, StoreError> {
+        let ret = sqlx::query_as(
+            "UPDATE urls SET deleted_at = ? WHERE domain = ? AND id = ? RETURNING *",
+        )
+        .bind(deleted.then(Utc::now))
+        .bind(&key.domain)
+        .bind(&key.id)
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(StoreError::NotFound)?;
+        Ok(ret)
+    }
+
+    async fn purge_expired(&self, before: DateTime<Utc>) -> Result<u64, StoreError> {
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM urls
+            WHERE expires_at <= ?
+                OR (max_clicks IS NOT NULL AND clicks >= max_clicks
+                    AND COALESCE(last_clicked_at, created_at) <= ?)
+            "#,
+        )
+        .bind(before)
+        .bind(before)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM clicks WHERE NOT EXISTS
+                (SELECT 1 FROM urls WHERE urls.domain = clicks.domain AND urls.id = clicks.url_id)
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected())
+    }
+
+    async fn record_clicks(&self, clicks: &[ClickEvent]) -> Result<(), StoreError> {
+        let mut query = QueryBuilder::new(
+            "INSERT INTO clicks (domain, url_id, clicked_at, referrer, user_agent, ip_hash) ",
+        );
+        query.push_values(clicks, |mut row, click| {
+            row.push_bind(&click.domain)
+                .push_bind(&click.url_id)
+                .push_bind(click.clicked_at)
+                .push_bind(&click.referrer)
+                .push_bind(&click.user_agent)
+                .push_bind(&click.ip_hash);
+        });
+        query.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn add_clicks(&self, counts: &[(LinkKey, i64)]) -> Result<(), StoreError> {
+        let now = Utc::now();
+        let mut tx = self.db.begin().await?;
+        for (key, n) in counts {
+            sqlx::query(
+                r#"
+                UPDATE urls SET clicks = clicks + ?, last_clicked_at = ?
+                WHERE domain = ? AND id = ?
+                "#,
+            )
+            .bind(n)
+            .bind(now)
+            .bind(&key.domain)
+            .bind(&key.id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn click_stats(
+        &self,
+        key: &LinkKey,
+        bucket: Bucket,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<ClickBucket>, StoreError> {
+        // timestamps are stored as rfc3339 text, so truncating is just formatting
+        let format = match bucket {
+            Bucket::Day => "%Y-%m-%dT00:00:00Z",
+            Bucket::Hour => "%Y-%m-%dT%H:00:00Z",
+        };
+        let ret = sqlx::query_as(
+            r#"
+            SELECT strftime(?, clicked_at) AS start, COUNT(*) AS clicks
+            FROM clicks
+            WHERE domain = ? AND url_id = ? AND clicked_at >= ?
+            GROUP BY start
+            ORDER BY start
+            "#,
+        )
+        .bind(format)
+        .bind(&key.domain)
+        .bind(&key.id)
+        .bind(since)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+}